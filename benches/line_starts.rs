@@ -0,0 +1,45 @@
+//! Throughput of [LineStarts::new] on multi megabyte inputs. Run with `cargo bench --bench line_starts`
+use source_map::{LineStarts, LineTerminators};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let code =
+        "function add(a, b) {\r\n\treturn a + b; // “sum”\r\n}\r\n\r\nconst x = add(1, 2);\n";
+    let prose = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.\n";
+    let minified = "var a=1,b=2;function c(d){return d*a+b}".repeat(40) + "\n";
+
+    for (name, unit) in [("code", code), ("prose", prose), ("minified", &minified)] {
+        let input = unit.repeat((8 * 1024 * 1024) / unit.len());
+
+        let baseline = throughput(&input, |input| {
+            std::iter::once(0)
+                .chain(input.match_indices('\n').map(|(idx, _)| idx + 1))
+                .collect::<Vec<_>>()
+                .len()
+        });
+        println!("{name:>8} match_indices('\\n') baseline: {baseline:>8.1} MB/s");
+
+        for terminators in [
+            LineTerminators::LineFeed,
+            LineTerminators::CarriageReturn,
+            LineTerminators::JavaScript,
+        ] {
+            let mb_per_second = throughput(&input, |input| {
+                LineStarts::new_with_line_terminators(input, terminators).line_count()
+            });
+            println!("{name:>8} {terminators:?}: {mb_per_second:>8.1} MB/s");
+        }
+    }
+}
+
+fn throughput(input: &str, f: impl Fn(&str) -> usize) -> f64 {
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        std::hint::black_box(f(std::hint::black_box(input)));
+        total += start.elapsed();
+    }
+    (input.len() as f64 * ITERATIONS as f64) / (1024.0 * 1024.0) / total.as_secs_f64()
+}
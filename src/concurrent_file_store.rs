@@ -0,0 +1,321 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock, RwLock,
+    },
+};
+
+use crate::{
+    source_id::StoreTag, Error, FileSystem, LineStarts, LineTerminators, Nullable, Source,
+    SourceId, SourceMetadata,
+};
+
+/// Size of the first segment. Each following segment is double the size of the previous
+const FIRST_SEGMENT_LENGTH: usize = 32;
+/// Enough segments for [SourceId::MAX_SOURCES] with `u32-source-ids`
+const SEGMENTS: usize = 28;
+const PATH_SHARDS: usize = 16;
+
+/// A [FileSystem] which can be shared between threads. Sources are added and read through `&self`
+/// (`FileSystem` is also implemented for `&ConcurrentFileStore`), without a lock around the
+/// sources. Sources are append only, they cannot be updated or removed.
+///
+/// Sources are stored in segments which double in size, so existing sources never move
+pub struct ConcurrentFileStore {
+    segments: [OnceLock<Box<[OnceLock<Source>]>>; SEGMENTS],
+    length: AtomicUsize,
+    /// [Normalised](crate::paths::normalise) paths, sharded by hash
+    paths: [RwLock<HashMap<PathBuf, SourceId>>; PATH_SHARDS],
+    line_terminators: LineTerminators,
+    tag: StoreTag,
+}
+
+impl Default for ConcurrentFileStore {
+    fn default() -> Self {
+        Self::new_with_line_terminators(LineTerminators::default())
+    }
+}
+
+impl ConcurrentFileStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_with_line_terminators(line_terminators: LineTerminators) -> Self {
+        Self {
+            segments: std::array::from_fn(|_| OnceLock::new()),
+            length: AtomicUsize::new(0),
+            paths: std::array::from_fn(|_| RwLock::default()),
+            line_terminators,
+            tag: StoreTag::new(),
+        }
+    }
+
+    /// Same as [FileSystem::new_source_id_with_line_starts] but through `&self`. Panics if the
+    /// store is full, see [ConcurrentFileStore::try_add_source]
+    pub fn add_source(&self, path: PathBuf, content: String) -> (SourceId, LineStarts) {
+        self.try_add_source(path, content)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Returns [Error::TooManySources] if the store already holds [SourceId::MAX_SOURCES] sources
+    pub fn try_add_source(
+        &self,
+        path: PathBuf,
+        content: String,
+    ) -> Result<(SourceId, LineStarts), Error> {
+        // Only increments if there is space, so `length` never goes past the last position
+        let position = self
+            .length
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |length| {
+                (length < SourceId::MAX_SOURCES).then_some(length + 1)
+            })
+            .map_err(|_| Error::TooManySources {
+                maximum: SourceId::MAX_SOURCES,
+            })?;
+        // Important that this is after. SourceId(0) is SourceId::NULL
+        let source_id = SourceId::from_index(position + 1, 0, self.tag);
+
+        let line_starts = LineStarts::new_with_line_terminators(&content, self.line_terminators);
+        let (segment, offset) = segment_and_offset(position);
+        let slots = self.segments[segment].get_or_init(|| {
+            (0..FIRST_SEGMENT_LENGTH << segment)
+                .map(|_| OnceLock::new())
+                .collect()
+        });
+
+        let key = crate::paths::normalise(&path);
+        let source = Source::new(path, content.into(), line_starts.clone());
+        if slots[offset].set(source).is_err() {
+            unreachable!("position {} assigned twice", position);
+        }
+        // After the slot is filled, so ids found by path can always be read
+        self.paths[path_shard(&key)]
+            .write()
+            .unwrap()
+            .insert(key, source_id);
+
+        Ok((source_id, line_starts))
+    }
+
+    /// Returns a possible [SourceId] for a path
+    pub fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
+        let key = crate::paths::normalise(path);
+        self.paths[path_shard(&key)]
+            .read()
+            .unwrap()
+            .get(&key)
+            .copied()
+    }
+
+    /// Number of sources added (including those currently being added on other threads)
+    pub fn len(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get_source(&self, source_id: SourceId) -> Result<&Source, Error> {
+        if source_id.is_null() {
+            return Err(Error::NullSourceId);
+        }
+        if source_id.store != self.tag {
+            return Err(Error::ForeignSourceId(source_id));
+        }
+        // Sources are never removed so all generations are 0
+        if source_id.generation != 0 || source_id.position() >= SourceId::MAX_SOURCES {
+            return Err(Error::UnknownSourceId(source_id));
+        }
+        let (segment, offset) = segment_and_offset(source_id.position());
+        self.segments[segment]
+            .get()
+            .and_then(|slots| slots[offset].get())
+            .ok_or(Error::UnknownSourceId(source_id))
+    }
+}
+
+/// Returns the segment and the position in the segment
+fn segment_and_offset(position: usize) -> (usize, usize) {
+    let shifted = position + FIRST_SEGMENT_LENGTH;
+    let segment = (usize::BITS - 1 - shifted.leading_zeros()) as usize
+        - FIRST_SEGMENT_LENGTH.trailing_zeros() as usize;
+    (segment, shifted - (FIRST_SEGMENT_LENGTH << segment))
+}
+
+fn path_shard(path: &Path) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish() as usize % PATH_SHARDS
+}
+
+impl FileSystem for ConcurrentFileStore {
+    fn new_source_id_with_line_starts(
+        &mut self,
+        path: PathBuf,
+        content: String,
+    ) -> (SourceId, LineStarts) {
+        self.add_source(path, content)
+    }
+
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        &self,
+        source_id: SourceId,
+        f: F,
+    ) -> Result<T, Error> {
+        self.get_source(source_id).map(f)
+    }
+
+    /// Only through `&mut ConcurrentFileStore`. Returns [Error::ReadOnly] through
+    /// `&ConcurrentFileStore`
+    fn update_metadata(
+        &mut self,
+        source_id: SourceId,
+        f: impl FnOnce(&mut SourceMetadata),
+    ) -> Result<(), Error> {
+        self.get_source(source_id)?;
+        let (segment, offset) = segment_and_offset(source_id.position());
+        let source = self.segments[segment]
+            .get_mut()
+            .and_then(|slots| slots[offset].get_mut())
+            .unwrap();
+        f(source.metadata_mut());
+        Ok(())
+    }
+}
+
+impl FileSystem for &'_ ConcurrentFileStore {
+    fn new_source_id_with_line_starts(
+        &mut self,
+        path: PathBuf,
+        content: String,
+    ) -> (SourceId, LineStarts) {
+        self.add_source(path, content)
+    }
+
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        &self,
+        source_id: SourceId,
+        f: F,
+    ) -> Result<T, Error> {
+        self.get_source(source_id).map(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments() {
+        assert_eq!(segment_and_offset(0), (0, 0));
+        assert_eq!(segment_and_offset(31), (0, 31));
+        assert_eq!(segment_and_offset(32), (1, 0));
+        assert_eq!(segment_and_offset(95), (1, 63));
+        assert_eq!(segment_and_offset(96), (2, 0));
+        let (last_segment, _) = segment_and_offset(u32::MAX as usize - 1);
+        assert!(last_segment < SEGMENTS);
+    }
+
+    #[test]
+    fn add_and_read_from_threads() {
+        const THREADS: usize = 8;
+        const SOURCES_PER_THREAD: usize = 500;
+
+        let store = ConcurrentFileStore::new();
+        let ids = std::thread::scope(|scope| {
+            let handles = (0..THREADS)
+                .map(|thread| {
+                    let mut fs = &store;
+                    scope.spawn(move || {
+                        (0..SOURCES_PER_THREAD)
+                            .map(|idx| {
+                                let content = format!("{thread}\n{idx}");
+                                let path = PathBuf::from(format!("{thread}/{idx}.ts"));
+                                let id = fs.new_source_id(path, content.clone());
+                                assert_eq!(fs.get_file_content(id), content);
+                                id
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(store.len(), THREADS * SOURCES_PER_THREAD);
+        let unique = ids.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(unique.len(), ids.len());
+
+        let id = store.get_source_at_path(Path::new("3/20.ts")).unwrap();
+        assert_eq!(store.get_file_content(id), "3\n20");
+        assert_eq!(
+            store.get_source_by_id(id, |source| source.line_starts.line_count()),
+            2
+        );
+    }
+
+    #[test]
+    fn read_by_path_while_adding() {
+        const SOURCES: usize = 2000;
+
+        let store = ConcurrentFileStore::new();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for idx in 0..SOURCES {
+                    store.add_source(format!("{idx}.ts").into(), idx.to_string());
+                }
+            });
+            scope.spawn(|| {
+                let mut found = 0;
+                while found < SOURCES {
+                    let path = PathBuf::from(format!("{found}.ts"));
+                    if let Some(id) = store.get_source_at_path(&path) {
+                        assert_eq!(store.try_get_source_by_id(id, |_| ()), Ok(()));
+                        assert_eq!(store.get_file_content(id), found.to_string());
+                        found += 1;
+                    }
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn metadata() {
+        let mut store = ConcurrentFileStore::new();
+        let (a, _) = store.add_source("a.ts".into(), String::new());
+        assert_eq!(
+            (&store).update_metadata(a, |metadata| metadata.version = Some(1)),
+            Err(Error::ReadOnly(a))
+        );
+        store
+            .update_metadata(a, |metadata| metadata.version = Some(1))
+            .unwrap();
+        assert_eq!(store.get_file_metadata(a).version, Some(1));
+    }
+
+    #[cfg(not(feature = "u32-source-ids"))]
+    #[test]
+    fn full_store() {
+        let store = ConcurrentFileStore::new();
+        for idx in 0..SourceId::MAX_SOURCES {
+            store.add_source(format!("{idx}.ts").into(), String::new());
+        }
+        assert_eq!(
+            store
+                .try_add_source("last.ts".into(), String::new())
+                .map(|(source_id, _)| source_id),
+            Err(Error::TooManySources {
+                maximum: SourceId::MAX_SOURCES
+            })
+        );
+        assert_eq!(store.len(), SourceId::MAX_SOURCES);
+    }
+}
@@ -0,0 +1,263 @@
+use std::convert::TryInto;
+
+/// Encoding of the bytes of a source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// Bytes `0x80` to `0x9F` are decoded as in Windows-1252 (as browsers do for Latin-1), so is
+    /// also suitable for legacy Windows files
+    Latin1,
+}
+
+impl SourceEncoding {
+    /// From a byte order mark, otherwise UTF-8 if the bytes are valid UTF-8, otherwise
+    /// [SourceEncoding::Latin1]. Also returns the length of the byte order mark
+    pub fn detect(bytes: &[u8]) -> (Self, usize) {
+        for encoding in [Self::Utf8, Self::Utf16Le, Self::Utf16Be] {
+            if bytes.starts_with(encoding.bom()) {
+                return (encoding, encoding.bom().len());
+            }
+        }
+        if std::str::from_utf8(bytes).is_ok() {
+            (Self::Utf8, 0)
+        } else {
+            (Self::Latin1, 0)
+        }
+    }
+
+    /// Byte order mark. Empty for [SourceEncoding::Latin1]
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Self::Utf8 => b"\xEF\xBB\xBF",
+            Self::Utf16Le => b"\xFF\xFE",
+            Self::Utf16Be => b"\xFE\xFF",
+            Self::Latin1 => b"",
+        }
+    }
+}
+
+/// How the content of a source was decoded from its original bytes. Translates byte offsets in
+/// the (UTF-8) content, which spans use, to and from offsets in the original bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decoding {
+    pub(crate) encoding: SourceEncoding,
+    pub(crate) bom_length: u8,
+    /// Sections where every character has the same width in the content and the original. Not
+    /// merged for characters of the same width in both, so offsets inside characters round down
+    pub(crate) runs: Vec<Run>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Run {
+    pub(crate) decoded: u32,
+    pub(crate) original: u32,
+    pub(crate) decoded_width: u8,
+    pub(crate) original_width: u8,
+}
+
+impl Decoding {
+    pub fn encoding(&self) -> SourceEncoding {
+        self.encoding
+    }
+
+    /// Whether the original bytes started with a byte order mark (which is not in the content)
+    pub fn has_bom(&self) -> bool {
+        self.bom_length != 0
+    }
+
+    /// Offset in the original bytes of a byte offset in the content
+    pub fn to_original_offset(&self, offset: usize) -> usize {
+        let index = self
+            .runs
+            .partition_point(|run| run.decoded as usize <= offset);
+        match index.checked_sub(1).map(|index| self.runs[index]) {
+            Some(run) => {
+                let characters = (offset - run.decoded as usize) / run.decoded_width as usize;
+                run.original as usize + characters * run.original_width as usize
+            }
+            None => self.bom_length as usize,
+        }
+    }
+
+    /// Byte offset in the content of an offset in the original bytes. Offsets inside a character
+    /// are moved to the start of it
+    pub fn to_decoded_offset(&self, original: usize) -> usize {
+        let index = self
+            .runs
+            .partition_point(|run| run.original as usize <= original);
+        match index.checked_sub(1).map(|index| self.runs[index]) {
+            Some(run) => {
+                let characters = (original - run.original as usize) / run.original_width as usize;
+                run.decoded as usize + characters * run.decoded_width as usize
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Decodes the bytes of a source. `encoding` overrides [SourceEncoding::detect] (a byte order mark
+/// for it is still skipped). Invalid sequences become U+FFFD.
+///
+/// Returns [None] for the [Decoding] if the content is the same as the bytes
+pub fn decode(bytes: &[u8], encoding: Option<SourceEncoding>) -> (String, Option<Decoding>) {
+    let (encoding, bom_length) = match encoding {
+        Some(encoding) if bytes.starts_with(encoding.bom()) => (encoding, encoding.bom().len()),
+        Some(encoding) => (encoding, 0),
+        None => SourceEncoding::detect(bytes),
+    };
+    let body = &bytes[bom_length..];
+
+    if let (SourceEncoding::Utf8, 0, Ok(content)) =
+        (encoding, bom_length, std::str::from_utf8(bytes))
+    {
+        return (content.to_owned(), None);
+    }
+
+    let mut decoder = Decoder {
+        content: String::with_capacity(body.len()),
+        original: bom_length,
+        runs: Vec::new(),
+    };
+    match encoding {
+        SourceEncoding::Utf8 => {
+            for chunk in body.utf8_chunks() {
+                for chr in chunk.valid().chars() {
+                    decoder.push(chr, chr.len_utf8());
+                }
+                if !chunk.invalid().is_empty() {
+                    decoder.push(char::REPLACEMENT_CHARACTER, chunk.invalid().len());
+                }
+            }
+        }
+        SourceEncoding::Utf16Le | SourceEncoding::Utf16Be => {
+            let units = body.chunks_exact(2).map(|unit| {
+                let unit = [unit[0], unit[1]];
+                if encoding == SourceEncoding::Utf16Le {
+                    u16::from_le_bytes(unit)
+                } else {
+                    u16::from_be_bytes(unit)
+                }
+            });
+            for result in char::decode_utf16(units) {
+                match result {
+                    Ok(chr) => decoder.push(chr, chr.len_utf16() * 2),
+                    Err(_) => decoder.push(char::REPLACEMENT_CHARACTER, 2),
+                }
+            }
+            if body.len() % 2 == 1 {
+                decoder.push(char::REPLACEMENT_CHARACTER, 1);
+            }
+        }
+        SourceEncoding::Latin1 => {
+            for byte in body {
+                decoder.push(latin1_to_char(*byte), 1);
+            }
+        }
+    }
+
+    let Decoder { content, runs, .. } = decoder;
+    let decoding = Decoding {
+        encoding,
+        bom_length: bom_length as u8,
+        runs,
+    };
+    (content, Some(decoding))
+}
+
+struct Decoder {
+    content: String,
+    original: usize,
+    runs: Vec<Run>,
+}
+
+impl Decoder {
+    fn push(&mut self, chr: char, original_width: usize) {
+        let widths = (chr.len_utf8() as u8, original_width as u8);
+        if self
+            .runs
+            .last()
+            .is_none_or(|run| (run.decoded_width, run.original_width) != widths)
+        {
+            self.runs.push(Run {
+                decoded: to_u32(self.content.len()),
+                original: to_u32(self.original),
+                decoded_width: widths.0,
+                original_width: widths.1,
+            });
+        }
+        self.content.push(chr);
+        self.original += original_width;
+    }
+}
+
+fn to_u32(offset: usize) -> u32 {
+    offset
+        .try_into()
+        .expect("source too large to decode (over 4GB)")
+}
+
+/// Windows-1252 for `0x80` to `0x9F`, otherwise the same code point
+fn latin1_to_char(byte: u8) -> char {
+    const WINDOWS_1252: [char; 32] = [
+        '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}',
+        '\u{8F}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}',
+        '\u{2014}', '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}',
+        '\u{178}',
+    ];
+    match byte {
+        0x80..=0x9F => WINDOWS_1252[(byte - 0x80) as usize],
+        byte => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detecting_and_decoding() {
+        assert_eq!(decode(b"let a;", None), ("let a;".to_owned(), None));
+
+        let (content, decoding) = decode(b"\xEF\xBB\xBFlet \xC3\xA4;", None);
+        let decoding = decoding.unwrap();
+        assert_eq!(content, "let ä;");
+        assert_eq!(decoding.encoding(), SourceEncoding::Utf8);
+        assert!(decoding.has_bom());
+        assert_eq!(decoding.to_original_offset(4), 7);
+        assert_eq!(decoding.to_original_offset(content.len()), 10);
+
+        let (content, decoding) = decode(b"caf\xE9 \x93x\x94", None);
+        assert_eq!(content, "café “x”");
+        assert_eq!(decoding.unwrap().encoding(), SourceEncoding::Latin1);
+
+        let (content, _) = decode(b"a\xFFb", Some(SourceEncoding::Utf8));
+        assert_eq!(content, "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        let text = "a😀\nä=b";
+        let mut bytes = SourceEncoding::Utf16Le.bom().to_vec();
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        let (content, decoding) = decode(&bytes, None);
+        let decoding = decoding.unwrap();
+        assert_eq!(content, text);
+        assert_eq!(decoding.encoding(), SourceEncoding::Utf16Le);
+
+        // (offset in content, offset in original)
+        let expected = [(0, 2), (1, 4), (5, 8), (6, 10), (8, 12), (9, 14), (10, 16)];
+        for (decoded, original) in expected {
+            assert_eq!(decoding.to_original_offset(decoded), original, "{decoded}");
+            assert_eq!(decoding.to_decoded_offset(original), decoded, "{original}");
+        }
+        // Inside the emoji
+        assert_eq!(decoding.to_decoded_offset(5), 1);
+
+        let mut bytes = SourceEncoding::Utf16Be.bom().to_vec();
+        bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        assert_eq!(decode(&bytes, None).0, text);
+    }
+}
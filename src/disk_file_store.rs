@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::{
+    source_id::StoreTag, Error, FileSystem, FileSystemWithPaths, LineStarts, LineTerminators,
+    Nullable, Source, SourceId, SourceMetadata,
+};
+
+/// A [FileSystem] which reads sources from a directory. [SourceId]s are assigned by path and content
+/// (and its [LineStarts]) is read on first access. Files are decoded with [crate::decode]
+pub struct DiskFileStore {
+    root: PathBuf,
+    entries: Vec<DiskEntry>,
+    paths: HashMap<PathBuf, SourceId>,
+    line_terminators: LineTerminators,
+    tag: StoreTag,
+}
+
+struct DiskEntry {
+    /// Relative to `root`
+    path: PathBuf,
+    source: OnceLock<Result<Source, Error>>,
+    /// Kept when the source is reloaded
+    metadata: SourceMetadata,
+}
+
+impl DiskFileStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::new_with_line_terminators(root, LineTerminators::default())
+    }
+
+    pub fn new_with_line_terminators(
+        root: impl Into<PathBuf>,
+        line_terminators: LineTerminators,
+    ) -> Self {
+        Self {
+            root: root.into(),
+            entries: Vec::new(),
+            paths: HashMap::new(),
+            line_terminators,
+            tag: StoreTag::new(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the [SourceId] for `path` (relative to the root, or absolute under it). Does not read
+    /// the file
+    pub fn get_or_create_source_id(&mut self, path: &Path) -> SourceId {
+        let path = self.relative_path(path);
+        if let Some(existing) = self.paths.get(&path) {
+            return *existing;
+        }
+        self.entries.push(DiskEntry {
+            path: path.clone(),
+            source: OnceLock::new(),
+            metadata: SourceMetadata::default(),
+        });
+        let source_id = SourceId::from_index(self.entries.len(), 0, self.tag);
+        self.paths.insert(path, source_id);
+        source_id
+    }
+
+    /// Returns a possible [SourceId] for a path
+    pub fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
+        self.paths.get(&self.relative_path(path)).copied()
+    }
+
+    /// Whether the content of the source has been read (or failed to be read)
+    pub fn is_loaded(&self, source_id: SourceId) -> bool {
+        self.get_entry(source_id)
+            .is_ok_and(|entry| entry.source.get().is_some())
+    }
+
+    /// Discards the cached content so it will be read again on next access
+    pub fn reload(&mut self, source_id: SourceId) -> Result<(), Error> {
+        self.get_entry(source_id)?;
+        self.entries[source_id.position()].source = OnceLock::new();
+        Ok(())
+    }
+
+    /// [Normalised](crate::paths::normalise) and relative to the root
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        let path = crate::paths::normalise(path);
+        match path.strip_prefix(crate::paths::normalise(&self.root)) {
+            Ok(relative) => crate::paths::normalise(relative),
+            Err(_) => path,
+        }
+    }
+
+    fn get_entry(&self, source_id: SourceId) -> Result<&DiskEntry, Error> {
+        if source_id.is_null() {
+            return Err(Error::NullSourceId);
+        }
+        if source_id.store != self.tag {
+            return Err(Error::ForeignSourceId(source_id));
+        }
+        self.entries
+            .get(source_id.position())
+            // Sources are never removed so all generations are 0
+            .filter(|_| source_id.generation == 0)
+            .ok_or(Error::UnknownSourceId(source_id))
+    }
+
+    fn read(&self, entry: &DiskEntry) -> Result<Source, Error> {
+        let bytes = std::fs::read(self.root.join(&entry.path)).map_err(|err| Error::Read {
+            path: entry.path.clone(),
+            kind: err.kind(),
+        })?;
+        let (content, decoding) = crate::decode(&bytes, None);
+        let line_starts = LineStarts::new_with_line_terminators(&content, self.line_terminators);
+        Ok(Source::new(entry.path.clone(), content.into(), line_starts)
+            .with_decoding(decoding)
+            .with_metadata(entry.metadata.clone()))
+    }
+}
+
+impl FileSystem for DiskFileStore {
+    /// Registers `path` with `content` rather than reading it from disk. If `path` already has a
+    /// [SourceId] then its content is replaced. As with other stores, the
+    /// [origin](SourceMetadata::origin) is not changed
+    fn new_source_id_with_line_starts(
+        &mut self,
+        path: PathBuf,
+        content: String,
+    ) -> (SourceId, LineStarts) {
+        let source_id = self.get_or_create_source_id(&path);
+        let line_starts = LineStarts::new_with_line_terminators(&content, self.line_terminators);
+        let entry = &mut self.entries[source_id.position()];
+        entry.source = OnceLock::from(Ok(Source::new(
+            entry.path.clone(),
+            content.into(),
+            line_starts.clone(),
+        )
+        .with_metadata(entry.metadata.clone())));
+        (source_id, line_starts)
+    }
+
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        &self,
+        source_id: SourceId,
+        f: F,
+    ) -> Result<T, Error> {
+        let entry = self.get_entry(source_id)?;
+        match entry.source.get_or_init(|| self.read(entry)) {
+            Ok(source) => Ok(f(source)),
+            Err(err) => Err(err.clone()),
+        }
+    }
+
+    /// Does not read the source
+    fn try_get_file_metadata(&self, source_id: SourceId) -> Result<SourceMetadata, Error> {
+        self.get_entry(source_id)
+            .map(|entry| entry.metadata.clone())
+    }
+
+    /// Does not read the source
+    fn update_metadata(
+        &mut self,
+        source_id: SourceId,
+        f: impl FnOnce(&mut SourceMetadata),
+    ) -> Result<(), Error> {
+        self.get_entry(source_id)?;
+        let entry = &mut self.entries[source_id.position()];
+        f(&mut entry.metadata);
+        if let Some(Ok(source)) = entry.source.get_mut() {
+            *source.metadata_mut() = entry.metadata.clone();
+        }
+        Ok(())
+    }
+}
+
+impl FileSystemWithPaths for DiskFileStore {
+    fn get_or_create_source_id(&mut self, path: &Path) -> SourceId {
+        DiskFileStore::get_or_create_source_id(self, path)
+    }
+
+    fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
+        DiskFileStore::get_source_at_path(self, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lazily_reads_from_disk() {
+        let root = std::env::temp_dir().join(format!("source-map-disk-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/a.ts"), "const a = 2;\nexport { a };").unwrap();
+
+        let mut fs = DiskFileStore::new(&root);
+        let a = fs.get_or_create_source_id(Path::new("src/a.ts"));
+        let missing = fs.get_or_create_source_id(Path::new("src/missing.ts"));
+
+        assert_eq!(fs.get_or_create_source_id(&root.join("src/a.ts")), a);
+        assert!(!fs.is_loaded(a));
+        assert!(fs.try_get_file_metadata(a).is_ok());
+        assert!(!fs.is_loaded(a));
+
+        assert_eq!(
+            fs.try_get_source_by_id(a, |source| source.line_starts.line_count()),
+            Ok(2)
+        );
+        assert!(fs.is_loaded(a));
+        assert_eq!(fs.get_file_path(a), Path::new("src/a.ts"));
+
+        assert_eq!(
+            fs.try_get_source_by_id(missing, |_| ()),
+            Err(Error::Read {
+                path: "src/missing.ts".into(),
+                kind: std::io::ErrorKind::NotFound
+            })
+        );
+
+        fs.update_metadata(a, |metadata| metadata.version = Some(1))
+            .unwrap();
+        std::fs::write(root.join("src/a.ts"), "changed").unwrap();
+        assert_ne!(fs.get_file_content(a), "changed");
+        fs.reload(a).unwrap();
+        assert_eq!(fs.get_file_content(a), "changed");
+        assert_eq!(fs.get_file_metadata(a).version, Some(1));
+
+        // The same origin as sources added to other stores
+        let b = fs.new_source_id("src/b.ts".into(), "let b;".into());
+        let mut other = crate::MapFileStore::<crate::NoPathMap>::default();
+        let other_b = other.new_source_id("src/b.ts".into(), "let b;".into());
+        assert_eq!(
+            fs.get_file_metadata(b).origin,
+            other.get_file_metadata(other_b).origin
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
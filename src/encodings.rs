@@ -39,3 +39,29 @@ impl StringEncoding for Utf8 {
         string.chars().take(length).map(char::len_utf8).count()
     }
 }
+
+/// The default position encoding for the language server protocol
+#[derive(Debug, PartialEq, Eq)]
+pub struct Utf16;
+
+impl StringEncoding for Utf16 {
+    fn new() -> Self {
+        Self
+    }
+
+    fn get_encoded_length(string: &str) -> usize {
+        string.chars().map(char::len_utf16).sum()
+    }
+
+    fn encoded_length_to_byte_count(string: &str, length: usize) -> usize {
+        let mut encoded = 0;
+        string
+            .chars()
+            .take_while(|chr| {
+                encoded += chr.len_utf16();
+                encoded <= length
+            })
+            .map(char::len_utf8)
+            .sum()
+    }
+}
@@ -0,0 +1,110 @@
+use crate::SourceId;
+use std::{fmt, io, path::PathBuf};
+
+/// Errors from looking up sources and positions. Returned by the `try_*` variants of methods which
+/// otherwise panic
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// [SourceId::NULL](crate::Nullable::NULL) does not reference a source
+    NullSourceId,
+    /// No source is registered under this id (for example it came from another [crate::FileSystem])
+    UnknownSourceId(SourceId),
+    /// The source was removed (and its index may have been reused)
+    StaleSourceId(SourceId),
+    /// The id was created by another store. Only detected with the `source-id-store-tags` feature
+    ForeignSourceId(SourceId),
+    /// The store does not support changing the source. See [crate::FileSystem::update_metadata]
+    ReadOnly(SourceId),
+    /// Byte position is past the end of the source
+    PositionOutOfBounds { position: usize, length: usize },
+    /// Range starts after it ends
+    InvalidRange { start: usize, end: usize },
+    /// Byte position is inside a multi-byte character
+    NotOnCharBoundary { position: usize },
+    /// A span (for example a semantic token) starts before the end of the previous one
+    OverlappingSpans { start: usize, previous_end: usize },
+    /// Line is past the last line of the source
+    LineOutOfBounds { line: usize, line_count: usize },
+    /// No source is registered at this path
+    NoSourceAtPath(PathBuf),
+    /// A source could not be renamed as there is already a source at this path
+    PathAlreadyExists(PathBuf),
+    /// Could not read the content of a source from disk
+    Read { path: PathBuf, kind: io::ErrorKind },
+    /// Bytes passed to [crate::MapFileStore::from_snapshot] are not a valid snapshot (or are from an
+    /// unsupported version)
+    InvalidSnapshot { reason: &'static str },
+    /// A segment passed to [crate::MapFileStore::new_concatenated_source_id] is invalid
+    InvalidSegment { index: usize, reason: &'static str },
+    /// The store already holds [SourceId::MAX_SOURCES] sources
+    TooManySources { maximum: usize },
+    /// Escape sequence (starting at `position`) which is not valid in the string literal. See
+    /// [crate::decode_escapes]
+    InvalidEscape { position: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NullSourceId => f.write_str("null source id does not reference a source"),
+            Error::UnknownSourceId(source_id) => write!(f, "no source found for {source_id:?}"),
+            Error::StaleSourceId(source_id) => write!(f, "{source_id:?} has been removed"),
+            Error::ForeignSourceId(source_id) => {
+                write!(f, "{source_id:?} was created by a different store")
+            }
+            Error::ReadOnly(source_id) => write!(f, "{source_id:?} cannot be changed"),
+            Error::PositionOutOfBounds { position, length } => {
+                write!(
+                    f,
+                    "position {position} out of bounds of source of length {length}"
+                )
+            }
+            Error::InvalidRange { start, end } => {
+                write!(f, "range {start}..{end} starts after it ends")
+            }
+            Error::NotOnCharBoundary { position } => {
+                write!(f, "position {position} is not on a character boundary")
+            }
+            Error::OverlappingSpans {
+                start,
+                previous_end,
+            } => {
+                write!(
+                    f,
+                    "span starting at {start} overlaps the previous span which ends at {previous_end}"
+                )
+            }
+            Error::LineOutOfBounds { line, line_count } => {
+                write!(
+                    f,
+                    "line {line} out of bounds of source with {line_count} lines"
+                )
+            }
+            Error::NoSourceAtPath(path) => write!(f, "no source at {}", path.display()),
+            Error::PathAlreadyExists(path) => {
+                write!(f, "there is already a source at {}", path.display())
+            }
+            Error::Read { path, kind } => {
+                write!(
+                    f,
+                    "could not read {}: {}",
+                    path.display(),
+                    io::Error::from(*kind)
+                )
+            }
+            Error::InvalidSnapshot { reason } => write!(f, "invalid snapshot: {reason}"),
+            Error::InvalidSegment { index, reason } => {
+                write!(f, "invalid segment {index}: {reason}")
+            }
+            Error::TooManySources { maximum } => {
+                write!(f, "too many sources (maximum {maximum})")
+            }
+            Error::InvalidEscape { position } => {
+                write!(f, "invalid escape sequence at position {position}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,316 @@
+use crate::{Error, FileSystem, OffsetMap, SpanWithSource};
+
+/// Which escape sequences a string literal can contain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscapeGrammar {
+    /// `\n`, `\xHH`, `\uHHHH` (surrogate pairs are combined), `\u{H...}`, line continuations and
+    /// any other character escaping itself. Legacy octal escapes (`\1` etc) are not supported
+    JavaScript,
+    /// `\n`, `\xHH` (up to `\x7F`), `\u{H...}` and line continuations (which also skip leading
+    /// whitespace on the next line)
+    Rust,
+    /// `\n`, `\"`, `\/`, `\uHHHH` (surrogate pairs are combined) etc
+    Json,
+}
+
+/// Cooks the content of a string literal (`literal` should not include the quotes). Also returns
+/// an [OffsetMap] which translates spans in the cooked string back to the raw source, where
+/// characters from escapes map to the whole escape.
+///
+/// Unpaired surrogates (which are allowed in JavaScript and JSON) become U+FFFD. Returns
+/// [Error::InvalidEscape] for escapes not in `grammar` and [Error::InvalidRange] if `literal`
+/// starts after it ends
+pub fn decode_escapes(
+    fs: &impl FileSystem,
+    literal: SpanWithSource,
+    grammar: EscapeGrammar,
+) -> Result<(String, OffsetMap), Error> {
+    fs.try_get_source_by_id(literal.source, |source| {
+        source.check_position(literal.start as usize)?;
+        source.check_position(literal.end as usize)?;
+        let raw = source
+            .content
+            .get(literal.start as usize..literal.end as usize)
+            .ok_or(Error::InvalidRange {
+                start: literal.start as usize,
+                end: literal.end as usize,
+            })?;
+
+        let mut map = OffsetMap::new(literal.source);
+        map.delete(literal.start as usize);
+        let mut cooked = String::with_capacity(raw.len());
+        let mut rest = raw;
+        while let Some(backslash) = rest.find('\\') {
+            cooked.push_str(&rest[..backslash]);
+            map.copy(backslash);
+
+            let escape = &rest[backslash + 1..];
+            let (chr, length) = match grammar {
+                EscapeGrammar::JavaScript => javascript_escape(escape),
+                EscapeGrammar::Rust => rust_escape(escape),
+                EscapeGrammar::Json => json_escape(escape),
+            }
+            .ok_or(Error::InvalidEscape {
+                position: literal.start as usize + (raw.len() - rest.len()) + backslash,
+            })?;
+            if let Some(chr) = chr {
+                cooked.push(chr);
+            }
+            map.replace(1 + length, chr.map_or(0, char::len_utf8));
+            rest = &escape[length..];
+        }
+        cooked.push_str(rest);
+        map.copy(rest.len());
+        Ok((cooked, map))
+    })?
+}
+
+// The following functions take the text after the `\`. They return the character (or `None` for
+// line continuations) and the length of the escape after the `\`, or `None` if it is invalid
+
+fn javascript_escape(escape: &str) -> Option<(Option<char>, usize)> {
+    let chr = escape.chars().next()?;
+    let cooked = match chr {
+        'n' => '\n',
+        'r' => '\r',
+        '\r' if escape[1..].starts_with('\n') => return Some((None, 2)),
+        '\r' | '\n' | '\u{2028}' | '\u{2029}' => return Some((None, chr.len_utf8())),
+        't' => '\t',
+        'b' => '\u{8}',
+        'f' => '\u{C}',
+        'v' => '\u{B}',
+        '0' if !escape[1..].starts_with(|chr: char| chr.is_ascii_digit()) => '\0',
+        '0' => return None,
+        '1'..='9' => return None,
+        'x' => return Some((Some(char::from_u32(hex(&escape[1..], 2)?)?), 3)),
+        'u' if escape[1..].starts_with('{') => {
+            let (value, length) = braced_hex(&escape[2..], false)?;
+            if value > 0x10FFFF {
+                return None;
+            }
+            let chr = char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+            return Some((Some(chr), length + 2));
+        }
+        'u' => return utf16_escape(escape),
+        chr => chr,
+    };
+    Some((Some(cooked), chr.len_utf8()))
+}
+
+fn rust_escape(escape: &str) -> Option<(Option<char>, usize)> {
+    let cooked = match escape.as_bytes().first()? {
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'\\' => '\\',
+        b'0' => '\0',
+        b'\'' => '\'',
+        b'"' => '"',
+        b'x' => {
+            let value = hex(&escape[1..], 2)?;
+            return (value <= 0x7F).then_some((Some(value as u8 as char), 3));
+        }
+        b'u' if escape[1..].starts_with('{') => {
+            let (value, length) = braced_hex(&escape[2..], true)?;
+            return Some((Some(char::from_u32(value)?), length + 2));
+        }
+        b'\n' | b'\r' => {
+            let skipped = escape.trim_start_matches([' ', '\t', '\n', '\r']);
+            return Some((None, escape.len() - skipped.len()));
+        }
+        _ => return None,
+    };
+    Some((Some(cooked), 1))
+}
+
+fn json_escape(escape: &str) -> Option<(Option<char>, usize)> {
+    let cooked = match escape.as_bytes().first()? {
+        b'"' => '"',
+        b'\\' => '\\',
+        b'/' => '/',
+        b'b' => '\u{8}',
+        b'f' => '\u{C}',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'u' => return utf16_escape(escape),
+        _ => return None,
+    };
+    Some((Some(cooked), 1))
+}
+
+/// `uHHHH`, including a following `\uHHHH` if they are a surrogate pair
+fn utf16_escape(escape: &str) -> Option<(Option<char>, usize)> {
+    let first = hex(&escape[1..], 4)? as u16;
+    let second = escape[5..]
+        .strip_prefix("\\u")
+        .and_then(|rest| hex(rest, 4))
+        .map(|second| second as u16);
+    match char::decode_utf16(std::iter::once(first).chain(second)).next()? {
+        Ok(chr) if chr.len_utf16() == 2 => Some((Some(chr), 11)),
+        Ok(chr) => Some((Some(chr), 5)),
+        Err(_) => Some((Some(char::REPLACEMENT_CHARACTER), 5)),
+    }
+}
+
+/// Exactly `digits` hex digits
+fn hex(text: &str, digits: usize) -> Option<u32> {
+    let text = text.get(..digits)?;
+    if text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        u32::from_str_radix(text, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// `H...}`. Returns the length including the `}`. Rust allows `_`s and up to six digits.
+/// JavaScript allows any number of leading zeros (the caller checks the value)
+fn braced_hex(text: &str, is_rust: bool) -> Option<(u32, usize)> {
+    let end = text.find('}')?;
+    let digits = &text[..end];
+    let is_valid = !digits.starts_with('_')
+        && digits
+            .bytes()
+            .all(|byte| byte.is_ascii_hexdigit() || (is_rust && byte == b'_'));
+    let digits = digits.replace('_', "");
+    // Over six digits (ignoring leading zeros in JavaScript) is over U+10FFFF
+    let significant = if is_rust {
+        &digits
+    } else {
+        digits.trim_start_matches('0')
+    };
+    if !is_valid || digits.is_empty() || significant.len() > 6 {
+        return None;
+    }
+    Some((u32::from_str_radix(&digits, 16).ok()?, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MapFileStore, NoPathMap, Span};
+
+    fn span(start: u32, end: u32) -> Span {
+        Span {
+            start,
+            end,
+            source: (),
+        }
+    }
+
+    #[test]
+    fn javascript_escapes() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id(
+            "a.js".into(),
+            r#"let a = "\u{1F600}\n\uD83D\uDE00x\\\
+y";"#
+                .into(),
+        );
+        let literal = SpanWithSource {
+            start: 9,
+            end: 38,
+            source,
+        };
+        let (cooked, map) = decode_escapes(&fs, literal, EscapeGrammar::JavaScript).unwrap();
+        assert_eq!(cooked, "😀\n😀x\\y");
+
+        let raw = |start, end| SpanWithSource { start, end, source };
+        // First emoji
+        assert_eq!(map.to_original_span(span(0, 4)), raw(9, 18));
+        // `\n`
+        assert_eq!(map.to_original_span(span(4, 5)), raw(18, 20));
+        // Surrogate pair
+        assert_eq!(map.to_original_span(span(5, 9)), raw(20, 32));
+        assert_eq!(map.to_original_span(span(9, 10)), raw(32, 33));
+        // `y` after the line continuation
+        assert_eq!(map.to_original_span(span(11, 12)), raw(37, 38));
+
+        assert_eq!(
+            decode_escapes(
+                &fs,
+                SpanWithSource {
+                    start: 9,
+                    end: 18,
+                    source,
+                },
+                EscapeGrammar::Json
+            ),
+            Err(Error::InvalidEscape { position: 9 })
+        );
+        assert_eq!(
+            decode_escapes(&fs, raw(18, 9), EscapeGrammar::JavaScript),
+            Err(Error::InvalidRange { start: 18, end: 9 })
+        );
+
+        // Any number of leading zeros, but Rust allows at most six digits
+        let source = fs.new_source_id("b.js".into(), r"\u{0000041}\u{110000}".into());
+        let decode = |end, grammar| {
+            decode_escapes(
+                &fs,
+                SpanWithSource {
+                    start: 0,
+                    end,
+                    source,
+                },
+                grammar,
+            )
+            .map(|(cooked, _)| cooked)
+        };
+        assert_eq!(decode(11, EscapeGrammar::JavaScript), Ok("A".into()));
+        assert_eq!(
+            decode(11, EscapeGrammar::Rust),
+            Err(Error::InvalidEscape { position: 0 })
+        );
+        assert_eq!(
+            decode(21, EscapeGrammar::JavaScript),
+            Err(Error::InvalidEscape { position: 11 })
+        );
+
+        // Legacy octal escapes, including those starting with `0`
+        for octal in [r"a\01", r"a\08"] {
+            let source = fs.new_source_id("c.js".into(), octal.into());
+            assert_eq!(
+                decode_escapes(
+                    &fs,
+                    SpanWithSource {
+                        start: 0,
+                        end: 4,
+                        source,
+                    },
+                    EscapeGrammar::JavaScript
+                ),
+                Err(Error::InvalidEscape { position: 1 })
+            );
+        }
+    }
+
+    #[test]
+    fn rust_and_json_escapes() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id("a.rs".into(), "\"a\\x41\\u{1_F6_00}\\\n    b\\'\"".into());
+        let whole = |end| SpanWithSource {
+            start: 1,
+            end,
+            source,
+        };
+        let (cooked, map) = decode_escapes(&fs, whole(26), EscapeGrammar::Rust).unwrap();
+        assert_eq!(cooked, "aA😀b'");
+        assert_eq!(map.to_original_span(span(1, 2)).start, 2);
+        assert_eq!(map.to_original_span(span(6, 7)).start, 23);
+        assert_eq!(
+            decode_escapes(&fs, whole(13), EscapeGrammar::Rust),
+            Err(Error::InvalidEscape { position: 6 })
+        );
+
+        let source = fs.new_source_id("a.json".into(), r#""\"\/\ud83d""#.into());
+        let literal = SpanWithSource {
+            start: 1,
+            end: 11,
+            source,
+        };
+        let (cooked, map) = decode_escapes(&fs, literal, EscapeGrammar::Json).unwrap();
+        assert_eq!(cooked, "\"/\u{FFFD}");
+        assert_eq!(map.to_original_span(span(2, 5)).start, 5);
+    }
+}
@@ -1,29 +1,360 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryInto,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 
-use crate::{lines_columns_indexes::LineStarts, SourceId, SpanWithSource};
+mod snapshot;
+
+use crate::{
+    encodings::StringEncoding,
+    lines_columns_indexes::{LineStarts, LineTerminators},
+    source_id::StoreTag,
+    Decoding, Error, Nullable, SourceEncoding, SourceId, SourceMetadata, Span, SpanWithSource,
+};
 
 pub struct Source {
     pub path: PathBuf,
-    pub content: String,
+    /// Shared, so can be handed out without copying. See [FileSystem::get_shared_file_content].
+    /// Edits change it in place unless it is shared, in which case it is copied first. This is why
+    /// it is not an `Arc<str>`, which cannot grow and so would be copied on every edit
+    pub content: Arc<String>,
     pub(crate) line_starts: LineStarts,
+    /// Computed on first use, as edits would otherwise rehash the whole content
+    content_hash: OnceLock<u64>,
+    revision: u64,
+    decoding: Option<Decoding>,
+    metadata: SourceMetadata,
+    /// Sorted and not overlapping
+    segments: Vec<Segment>,
+}
+
+/// A section of a source which was copied from another source. See
+/// [MapFileStore::new_concatenated_source_id]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Position in the source it was copied into
+    pub span: Span,
+    /// Where it was copied from. The same length as `span`
+    pub original: SpanWithSource,
+    /// [Source::revision] of the original when the segment was added. Set by the store
+    original_revision: u64,
+}
+
+impl Segment {
+    pub fn new(span: Span, original: SpanWithSource) -> Self {
+        Self {
+            span,
+            original,
+            original_revision: 0,
+        }
+    }
+
+    /// [Source::revision] of the original when the segment was added. The segment is not followed
+    /// (by [FileSystem::resolve_original_span]) once the original has changed
+    pub fn original_revision(&self) -> u64 {
+        self.original_revision
+    }
+}
+
+/// Segments which are copied from segments more than this deep are treated as invalid. Stops
+/// cycles (which can only come from a corrupt store) from looping forever
+const MAX_SEGMENT_DEPTH: usize = 64;
+
+impl Source {
+    pub(crate) fn new(path: PathBuf, content: Arc<String>, line_starts: LineStarts) -> Self {
+        Self {
+            path,
+            content_hash: OnceLock::new(),
+            content,
+            line_starts,
+            revision: 0,
+            decoding: None,
+            metadata: SourceMetadata::default(),
+            segments: Vec::new(),
+        }
+    }
+
+    /// Sections copied from other sources. See [MapFileStore::new_concatenated_source_id]. Not
+    /// kept after the content is changed
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The section of another source which this source was created from. See
+    /// [MapFileStore::new_embedded_source_id]
+    pub fn parent(&self) -> Option<SpanWithSource> {
+        match self.segments.as_slice() {
+            [segment]
+                if segment.span.start == 0 && segment.span.end as usize == self.content.len() =>
+            {
+                Some(segment.original)
+            }
+            _ => None,
+        }
+    }
+
+    /// Position of `span` in the source the segment it starts in was copied from (and the index
+    /// of the segment). The end is limited to the end of that segment. [None] if `span` does not
+    /// start in a segment
+    pub(crate) fn resolve_segment(&self, span: SpanWithSource) -> Option<(SpanWithSource, usize)> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.span.start <= span.start)
+            .checked_sub(1)?;
+        let segment = self.segments.get(index)?;
+        if span.start > segment.span.end {
+            return None;
+        }
+        let end = span.end.clamp(span.start, segment.span.end);
+        let original = SpanWithSource {
+            start: segment.original.start + (span.start - segment.span.start),
+            end: segment.original.start + (end - segment.span.start),
+            source: segment.original.source,
+        };
+        Some((original, index))
+    }
+
+    pub(crate) fn with_metadata(self, metadata: SourceMetadata) -> Self {
+        Self { metadata, ..self }
+    }
+
+    pub fn metadata(&self) -> &SourceMetadata {
+        &self.metadata
+    }
+
+    pub(crate) fn metadata_mut(&mut self) -> &mut SourceMetadata {
+        &mut self.metadata
+    }
+
+    /// `content` was decoded from bytes. See [crate::decode]
+    pub(crate) fn with_decoding(self, decoding: Option<Decoding>) -> Self {
+        Self { decoding, ..self }
+    }
+
+    /// How the content was decoded, if it is not the same as the original bytes. Not kept after
+    /// the content is changed
+    pub fn decoding(&self) -> Option<&Decoding> {
+        self.decoding.as_ref()
+    }
+
+    /// Hash of `content`. Stable between runs, platforms and versions of this crate, so can be
+    /// persisted. Not cryptographic
+    pub fn content_hash(&self) -> u64 {
+        *self
+            .content_hash
+            .get_or_init(|| content_hash(&self.content))
+    }
+
+    /// Revision of the [MapFileStore] when this source was added or last changed. Always `0` for
+    /// sources from other stores
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Call after `content` is changed. `line_starts` should already be updated
+    fn content_changed(&mut self, revision: u64) {
+        self.content_hash = OnceLock::new();
+        self.revision = revision;
+        // No longer matches the original bytes or sources
+        self.decoding = None;
+        self.segments.clear();
+    }
+
+    /// Checks `position` can be used to slice `content`
+    pub(crate) fn check_position(&self, position: usize) -> Result<(), Error> {
+        check_position(&self.content, position)
+    }
+
+    /// Returns the byte index of the start of `line`
+    pub(crate) fn get_line_start(&self, line: usize) -> Result<usize, Error> {
+        self.line_starts
+            .get_line_start(line)
+            .ok_or(Error::LineOutOfBounds {
+                line,
+                line_count: self.line_starts.line_count(),
+            })
+    }
+
+    /// Returns the line and column (in the `T` encoding) of a byte position
+    pub(crate) fn get_line_and_column<T: StringEncoding>(
+        &self,
+        position: usize,
+    ) -> Result<(u32, u32), Error> {
+        self.check_position(position)?;
+        let line = self.line_starts.get_line_pos_is_on(position);
+        let line_start = self.line_starts.get_line_start(line).unwrap();
+        let column = T::get_encoded_length(&self.content[line_start..position]);
+        Ok((line as u32, column as u32))
+    }
+
+    /// Returns the byte position of a line and column (in the `T` encoding)
+    pub(crate) fn get_byte_position<T: StringEncoding>(
+        &self,
+        line: u32,
+        column: u32,
+    ) -> Result<u32, Error> {
+        let line_start = self.get_line_start(line as usize)?;
+        let column_length =
+            T::encoded_length_to_byte_count(&self.content[line_start..], column as usize);
+        Ok((line_start + column_length) as u32)
+    }
+}
+
+/// Checks `position` can be used to slice `content`
+fn check_position(content: &str, position: usize) -> Result<(), Error> {
+    if position > content.len() {
+        Err(Error::PositionOutOfBounds {
+            position,
+            length: content.len(),
+        })
+    } else if !content.is_char_boundary(position) {
+        Err(Error::NotOnCharBoundary { position })
+    } else {
+        Ok(())
+    }
+}
+
+/// Follows the segments `span` is in while the originals are unchanged. If `parents_only`, only
+/// follows segments covering the whole source (see [Source::parent])
+fn follow_segments(
+    fs: &impl FileSystem,
+    mut span: SpanWithSource,
+    parents_only: bool,
+) -> Result<SpanWithSource, Error> {
+    for _ in 0..MAX_SEGMENT_DEPTH {
+        let next = fs.try_get_source_by_id(span.source, |source| {
+            if parents_only && source.parent().is_none() {
+                return None;
+            }
+            let (original, index) = source.resolve_segment(span)?;
+            Some((original, source.segments[index].original_revision))
+        })?;
+        let Some((original, revision)) = next else {
+            return Ok(span);
+        };
+        // The content was copied from an earlier version, so may not be the same
+        if fs.try_get_source_by_id(original.source, Source::revision)? != revision {
+            return Ok(span);
+        }
+        span = original;
+    }
+    let index = fs
+        .try_get_source_by_id(span.source, |source| source.resolve_segment(span))?
+        .map_or(0, |(_, index)| index);
+    Err(Error::InvalidSegment {
+        index,
+        reason: "nested too deeply or cyclic",
+    })
+}
+
+/// 64 bit FNV-1a
+fn content_hash(content: &str) -> u64 {
+    content.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
 }
 
 #[cfg(feature = "global-source-filesystem")]
 pub mod global_store {
     use super::*;
+    use std::{
+        cell::RefCell,
+        marker::PhantomData,
+        sync::{Arc, RwLock},
+    };
 
+    /// A [FileSystem] shared by the whole process, or the store entered on the current thread with
+    /// [GlobalStore::scope] or [ScopedGlobalStore::enter].
+    ///
+    /// Scopes are per thread, not per async task. A task which can move between threads (or
+    /// interleave with other tasks on one thread) should enter its store in each poll rather than
+    /// holding a guard across `.await`s (the guard is not `Send` so cannot be held by such tasks)
     pub struct GlobalStore;
 
     #[cfg(feature = "global-source-filesystem")]
-    static SOURCE_IDS_TO_FILES: std::sync::RwLock<MapFileStore<NoPathMap>> =
-        std::sync::RwLock::new(MapFileStore {
-            sources: Vec::new(),
-            mappings: NoPathMap,
-        });
+    static SOURCE_IDS_TO_FILES: RwLock<MapFileStore<NoPathMap>> = RwLock::new(MapFileStore {
+        sources: Vec::new(),
+        removed: Vec::new(),
+        removals: Vec::new(),
+        next_generation: 0,
+        revision: 0,
+        mappings: NoPathMap,
+        line_terminators: LineTerminators::LineFeed,
+        tag: StoreTag::UNTAGGED,
+    });
+
+    thread_local! {
+        static SCOPE: RefCell<Option<ScopedGlobalStore>> = const { RefCell::new(None) };
+    }
+
+    fn with_store<T>(f: impl FnOnce(&RwLock<MapFileStore<NoPathMap>>) -> T) -> T {
+        match SCOPE.with(|scope| scope.borrow().clone()) {
+            Some(scoped) => f(&scoped.0),
+            None => f(&SOURCE_IDS_TO_FILES),
+        }
+    }
+
+    impl GlobalStore {
+        /// [GlobalStore] uses a new empty store on the current thread until the guard is dropped.
+        /// For example for a test or compilation session
+        pub fn scope() -> GlobalStoreGuard {
+            ScopedGlobalStore::default().enter()
+        }
+
+        /// Removes all sources from the store in use on the current thread. Existing [SourceId]s
+        /// return [Error::UnknownSourceId] (or [Error::StaleSourceId] once a source is added at
+        /// their position) rather than referencing sources added later
+        pub fn reset() {
+            with_store(|store| store.write().unwrap().clear())
+        }
+
+        /// Lines of sources added after this to the store in use on the current thread will end at
+        /// `line_terminators` rather than just `\n`
+        pub fn set_line_terminators(line_terminators: LineTerminators) {
+            with_store(|store| store.write().unwrap().line_terminators = line_terminators)
+        }
+    }
+
+    /// A store which [GlobalStore] can use instead of the process-wide store. Can be cloned and
+    /// entered on several threads (for example by worker threads of the same compilation session)
+    #[derive(Clone, Default)]
+    pub struct ScopedGlobalStore(Arc<RwLock<MapFileStore<NoPathMap>>>);
+
+    impl ScopedGlobalStore {
+        /// Lines of sources in this store will end at `line_terminators` rather than just `\n`
+        pub fn new_with_line_terminators(line_terminators: LineTerminators) -> Self {
+            Self(Arc::new(RwLock::new(
+                MapFileStore::new_with_line_terminators(line_terminators),
+            )))
+        }
+
+        /// [GlobalStore] uses this store on the current thread until the guard is dropped
+        pub fn enter(&self) -> GlobalStoreGuard {
+            let previous = SCOPE.with(|scope| scope.borrow_mut().replace(self.clone()));
+            GlobalStoreGuard {
+                previous,
+                _not_send: PhantomData,
+            }
+        }
+    }
+
+    /// Restores the previous store of the thread on drop
+    #[must_use = "the store is only used until the guard is dropped"]
+    pub struct GlobalStoreGuard {
+        previous: Option<ScopedGlobalStore>,
+        /// Must be dropped on the thread it was created on
+        _not_send: PhantomData<*const ()>,
+    }
+
+    impl Drop for GlobalStoreGuard {
+        fn drop(&mut self) {
+            let previous = self.previous.take();
+            SCOPE.with(|scope| *scope.borrow_mut() = previous);
+        }
+    }
 
     impl FileSystem for GlobalStore {
         fn new_source_id_with_line_starts(
@@ -31,29 +362,141 @@ pub mod global_store {
             path: PathBuf,
             content: String,
         ) -> (SourceId, LineStarts) {
-            SOURCE_IDS_TO_FILES
-                .write()
-                .unwrap()
-                .new_source_id_with_line_starts(path, content)
+            with_store(|store| {
+                store
+                    .write()
+                    .unwrap()
+                    .new_source_id_with_line_starts(path, content)
+            })
         }
 
-        fn get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
             &self,
             source_id: SourceId,
             f: F,
-        ) -> T {
-            SOURCE_IDS_TO_FILES
-                .read()
-                .unwrap()
-                .get_source_by_id(source_id, f)
+        ) -> Result<T, Error> {
+            with_store(|store| store.read().unwrap().try_get_source_by_id(source_id, f))
+        }
+
+        fn update_metadata(
+            &mut self,
+            source_id: SourceId,
+            f: impl FnOnce(&mut SourceMetadata),
+        ) -> Result<(), Error> {
+            with_store(|store| store.write().unwrap().update_metadata(source_id, f))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn scopes() {
+            let add = || GlobalStore.new_source_id("a.ts".into(), "let a;".into());
+
+            let _guard = GlobalStore::scope();
+            let a = add();
+            let session = ScopedGlobalStore::default();
+            let b = std::thread::spawn({
+                let session = session.clone();
+                move || {
+                    let _guard = session.enter();
+                    add()
+                }
+            })
+            .join()
+            .unwrap();
+
+            // Both are the first source in their store
+            assert_eq!(a.position(), b.position());
+            let second = {
+                let _inner = session.enter();
+                assert_eq!(GlobalStore.get_file_content(b), "let a;");
+                add()
+            };
+            assert_eq!(second.position(), 1);
+            assert!(matches!(
+                GlobalStore.try_get_source_by_id(second, |_| ()),
+                Err(Error::UnknownSourceId(_) | Error::ForeignSourceId(_))
+            ));
+
+            GlobalStore::reset();
+            assert_eq!(
+                GlobalStore.try_get_source_by_id(a, |_| ()),
+                Err(Error::UnknownSourceId(a))
+            );
+            let c = add();
+            assert_eq!(c.position(), a.position());
+            assert_eq!(
+                GlobalStore.try_get_source_by_id(a, |_| ()),
+                Err(Error::StaleSourceId(a))
+            );
+        }
+
+        #[test]
+        fn line_terminators() {
+            let line_count = |source| {
+                GlobalStore.get_source_by_id(source, |source| source.line_starts.line_count())
+            };
+            let _guard =
+                ScopedGlobalStore::new_with_line_terminators(LineTerminators::JavaScript).enter();
+            let a = GlobalStore.new_source_id("a.js".into(), "a\rb\u{2028}c".into());
+            assert_eq!(line_count(a), 3);
+
+            let _guard = GlobalStore::scope();
+            GlobalStore::set_line_terminators(LineTerminators::CarriageReturn);
+            let b = GlobalStore.new_source_id("b.js".into(), "a\rb\u{2028}c".into());
+            assert_eq!(line_count(b), 2);
         }
     }
 }
 
 #[derive(Default)]
 pub struct MapFileStore<T> {
-    sources: Vec<Source>,
+    sources: Vec<Slot>,
+    /// Positions of removed sources in `sources`, which are reused for new sources
+    removed: Vec<usize>,
+    /// Revision when each source was removed and its id. Sorted by revision. See
+    /// [MapFileStore::removed_since]
+    removals: Vec<(u64, SourceId)>,
+    /// Generation for slots added to the end of `sources`. Higher than any slot removed by
+    /// [MapFileStore::compact], so their ids are not reused
+    next_generation: u16,
+    /// Incremented on every addition, change or removal of a source
+    revision: u64,
     mappings: T,
+    line_terminators: LineTerminators,
+    tag: StoreTag,
+}
+
+/// A source, or a tombstone if the source was removed
+struct Slot {
+    generation: u16,
+    source: Option<Source>,
+}
+
+impl<T: Default> MapFileStore<T> {
+    /// Lines of sources in this store will end at `line_terminators` rather than just `\n`
+    pub fn new_with_line_terminators(line_terminators: LineTerminators) -> Self {
+        Self::new_with_path_map(T::default(), line_terminators)
+    }
+}
+
+impl<T> MapFileStore<T> {
+    /// For configured path maps. For example [WithPathMap::case_insensitive]
+    pub fn new_with_path_map(mappings: T, line_terminators: LineTerminators) -> Self {
+        Self {
+            sources: Vec::new(),
+            removed: Vec::new(),
+            removals: Vec::new(),
+            next_generation: 0,
+            revision: 0,
+            mappings,
+            line_terminators,
+            tag: StoreTag::new(),
+        }
+    }
 }
 
 pub trait FileSystem: Sized {
@@ -68,15 +511,34 @@ pub trait FileSystem: Sized {
         content: String,
     ) -> (SourceId, LineStarts);
 
+    /// Runs `f` with the source. Returns an error if `source_id` is [SourceId::NULL](Nullable::NULL)
+    /// or does not exist in this store.
+    ///
+    /// Implementors must implement this or [FileSystem::get_source_by_id] (each defaults to the
+    /// other). The default calls [FileSystem::get_source_by_id], so panics rather than returning an
+    /// error
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        &self,
+        source_id: SourceId,
+        f: F,
+    ) -> Result<T, Error> {
+        Ok(self.get_source_by_id(source_id, f))
+    }
+
+    /// Panicking version of [FileSystem::try_get_source_by_id]
     fn get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
         &self,
         source_id: SourceId,
         f: F,
-    ) -> T;
+    ) -> T {
+        self.try_get_source_by_id(source_id, f)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
 
+    /// Note that this does clone the content. See [FileSystem::get_shared_file_content]
     fn get_file_path_and_content(&self, source_id: SourceId) -> (PathBuf, String) {
         self.get_source_by_id(source_id, |Source { path, content, .. }| {
-            (path.to_owned(), content.to_owned())
+            (path.to_owned(), content.to_string())
         })
     }
 
@@ -84,8 +546,53 @@ pub trait FileSystem: Sized {
         self.get_source_by_id(source_id, |source| source.path.to_owned())
     }
 
+    /// Stores which load content lazily can override this to not load it
+    fn try_get_file_metadata(&self, source_id: SourceId) -> Result<SourceMetadata, Error> {
+        self.try_get_source_by_id(source_id, |source| source.metadata.clone())
+    }
+
+    fn get_file_metadata(&self, source_id: SourceId) -> SourceMetadata {
+        self.try_get_file_metadata(source_id)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Changes the [SourceMetadata] of a source. For example setting the LSP version after a
+    /// change. The default returns [Error::ReadOnly], for stores which cannot change sources
+    fn update_metadata(
+        &mut self,
+        source_id: SourceId,
+        f: impl FnOnce(&mut SourceMetadata),
+    ) -> Result<(), Error> {
+        let _ = f;
+        self.try_get_source_by_id(source_id, |_| ())?;
+        Err(Error::ReadOnly(source_id))
+    }
+
+    /// Note that this does clone the content. See [FileSystem::get_shared_file_content]
     fn get_file_content(&self, source_id: SourceId) -> String {
-        self.get_source_by_id(source_id, |source| source.content.to_owned())
+        self.get_source_by_id(source_id, |source| source.content.to_string())
+    }
+
+    /// Returns the content without copying it. The content is kept if the source is later updated
+    fn get_shared_file_content(&self, source_id: SourceId) -> Arc<String> {
+        self.get_source_by_id(source_id, |source| source.content.clone())
+    }
+
+    /// For a span in a [Segment] of an embedded or concatenated source (see
+    /// [MapFileStore::new_embedded_source_id] and [MapFileStore::new_concatenated_source_id]), the
+    /// span in the source it was copied from (following segments of that source). Otherwise returns
+    /// `span`
+    ///
+    /// Segments whose original has changed since they were added are not followed. Returns
+    /// [Error::InvalidSegment] if segments are nested too deeply (or form a cycle)
+    fn try_resolve_original_span(&self, span: SpanWithSource) -> Result<SpanWithSource, Error> {
+        follow_segments(self, span, false)
+    }
+
+    /// Panicking version of [FileSystem::try_resolve_original_span]
+    fn resolve_original_span(&self, span: SpanWithSource) -> SpanWithSource {
+        self.try_resolve_original_span(span)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 
     fn get_file_whole_span(&self, source_id: SourceId) -> SpanWithSource {
@@ -100,7 +607,7 @@ pub trait FileSystem: Sized {
         })
     }
 
-    /// Note that this does clone the result
+    /// Note that this does clone the result. Use [FileSystem::get_source_by_id] to borrow the content
     fn get_file_slice<I: std::slice::SliceIndex<str>>(
         &self,
         source_id: SourceId,
@@ -113,141 +620,667 @@ pub trait FileSystem: Sized {
     }
 
     #[cfg(feature = "codespan-reporting")]
-    fn into_code_span_store(&self) -> CodeSpanStore<Self> {
+    #[allow(clippy::wrong_self_convention)]
+    fn into_code_span_store(&self) -> CodeSpanStore<'_, Self> {
         CodeSpanStore(self)
     }
 }
 
+/// A [FileSystem] which can look up and assign [SourceId]s by path, before the content is known
+pub trait FileSystemWithPaths: FileSystem {
+    /// Returns the existing [SourceId] for `path` or registers `path`
+    fn get_or_create_source_id(&mut self, path: &Path) -> SourceId;
+
+    /// Returns a possible [SourceId] for a path
+    fn get_source_at_path(&self, path: &Path) -> Option<SourceId>;
+}
+
 impl<M: PathMap> FileSystem for MapFileStore<M> {
     fn new_source_id_with_line_starts(
         &mut self,
         path: PathBuf,
         content: String,
     ) -> (SourceId, LineStarts) {
-        let line_starts = LineStarts::new(&content);
-        let source = Source {
-            path: path.clone(),
-            content,
-            line_starts: line_starts.clone(),
+        let line_starts = LineStarts::new_with_line_terminators(&content, self.line_terminators);
+        let source = Some(Source {
+            revision: self.next_revision(),
+            ..Source::new(path.clone(), content.into(), line_starts.clone())
+        });
+
+        let source_id = if let Some(position) = self.removed.pop() {
+            let slot = &mut self.sources[position];
+            slot.generation += 1;
+            slot.source = source;
+            SourceId::from_index(position + 1, slot.generation, self.tag)
+        } else {
+            self.sources.push(Slot {
+                generation: self.next_generation,
+                source,
+            });
+            // Import that this is after. SourceId(0) is SourceId::NULL
+            SourceId::from_index(self.sources.len(), self.next_generation, self.tag)
         };
-        self.sources.push(source);
-        let source_id = SourceId(self.sources.len().try_into().unwrap());
         self.mappings.set_path(path, source_id);
 
-        // Import that this is after. SourceId(0) is SourceId::NULL
         (source_id, line_starts)
     }
 
-    fn get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
         &self,
         source_id: SourceId,
         f: F,
-    ) -> T {
-        f(&self.sources[source_id.0 as usize - 1])
+    ) -> Result<T, Error> {
+        self.get_source(source_id).map(f)
     }
+
+    fn update_metadata(
+        &mut self,
+        source_id: SourceId,
+        f: impl FnOnce(&mut SourceMetadata),
+    ) -> Result<(), Error> {
+        f(&mut self.get_source_mut(source_id)?.metadata);
+        Ok(())
+    }
+}
+
+/// What to do when a source is renamed to a path which already has a source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameConflict {
+    /// Return [Error::PathAlreadyExists]
+    Error,
+    /// Remove the existing source
+    Overwrite,
+    /// Leave both sources where they are
+    Skip,
 }
 
 #[derive(Default)]
 pub struct NoPathMap;
 
+/// Paths are [normalised](crate::paths::normalise), so `./a.ts` and `a.ts` are the same source
 #[derive(Default)]
-pub struct WithPathMap(HashMap<PathBuf, SourceId>);
+pub struct WithPathMap {
+    paths: HashMap<PathBuf, SourceId>,
+    case_insensitive: bool,
+}
+
+impl WithPathMap {
+    /// Also ignores the case of paths, for case insensitive file systems. Use with
+    /// [MapFileStore::new_with_path_map]
+    pub fn case_insensitive() -> Self {
+        Self {
+            paths: HashMap::new(),
+            case_insensitive: true,
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<SourceId> {
+        self.paths.get(&self.key(path)).copied()
+    }
+
+    fn key(&self, path: &Path) -> PathBuf {
+        if self.case_insensitive {
+            crate::paths::normalise_case_insensitive(path)
+        } else {
+            crate::paths::normalise(path)
+        }
+    }
+}
 
 impl PathMap for NoPathMap {
     fn set_path(&mut self, _path: PathBuf, _source: SourceId) {}
+
+    fn remove_path(&mut self, _path: &Path, _source: SourceId) {}
 }
 
 impl PathMap for WithPathMap {
     fn set_path(&mut self, path: PathBuf, source: SourceId) {
-        self.0.insert(path, source);
+        let key = self.key(&path);
+        self.paths.insert(key, source);
+    }
+
+    fn entries(&self) -> Vec<(&Path, SourceId)> {
+        self.paths
+            .iter()
+            .map(|(path, source)| (path.as_path(), *source))
+            .collect()
+    }
+
+    fn remove_path(&mut self, path: &Path, source: SourceId) {
+        let key = self.key(path);
+        if self.paths.get(&key) == Some(&source) {
+            self.paths.remove(&key);
+        }
     }
 }
 
 pub trait PathMap {
     fn set_path(&mut self, path: PathBuf, source: SourceId);
+
+    /// Removes `path` if it maps to `source`
+    fn remove_path(&mut self, path: &Path, source: SourceId);
+
+    /// All paths and their sources. Used by [MapFileStore::to_snapshot]
+    fn entries(&self) -> Vec<(&Path, SourceId)> {
+        Vec::new()
+    }
 }
 
 impl<T: PathMap> MapFileStore<T> {
+    fn get_slot(&self, id: SourceId) -> Result<&Slot, Error> {
+        if id.is_null() {
+            return Err(Error::NullSourceId);
+        }
+        if id.store != self.tag {
+            return Err(Error::ForeignSourceId(id));
+        }
+        self.sources
+            .get(id.position())
+            .ok_or(Error::UnknownSourceId(id))
+    }
+
+    fn get_source(&self, id: SourceId) -> Result<&Source, Error> {
+        let slot = self.get_slot(id)?;
+        slot.source
+            .as_ref()
+            .filter(|_| slot.generation == id.generation)
+            .ok_or(Error::StaleSourceId(id))
+    }
+
+    fn get_source_mut(&mut self, id: SourceId) -> Result<&mut Source, Error> {
+        self.get_source(id)?;
+        Ok(self.sources[id.position()].source.as_mut().unwrap())
+    }
+
+    /// Decodes `bytes` (see [crate::decode]) and adds it as a source. Offsets in the original bytes
+    /// are available through [Source::decoding]
+    pub fn new_source_id_from_bytes(
+        &mut self,
+        path: PathBuf,
+        bytes: &[u8],
+        encoding: Option<SourceEncoding>,
+    ) -> SourceId {
+        let (content, decoding) = crate::decode(bytes, encoding);
+        let source_id = self.new_source_id(path, content);
+        let source = self.sources[source_id.position()].source.take().unwrap();
+        self.sources[source_id.position()].source = Some(source.with_decoding(decoding));
+        source_id
+    }
+
+    /// Adds a source for a section of another source. For example a `<script>` in a HTML file or a
+    /// code block in Markdown. Spans in the embedded source are converted to positions in `parent`
+    /// (by [FileSystem::resolve_original_span]), so diagnostics and source maps refer to `parent`.
+    ///
+    /// The content is copied from `parent`, so should be added again if `parent` changes. Its
+    /// [origin](SourceMetadata::origin) is [SourceOrigin::Virtual](crate::SourceOrigin::Virtual)
+    pub fn new_embedded_source_id(
+        &mut self,
+        path: PathBuf,
+        parent: SpanWithSource,
+    ) -> Result<SourceId, Error> {
+        let source = self.get_source(parent.source)?;
+        source.check_position(parent.end as usize)?;
+        source.check_position(parent.start as usize)?;
+        let content = source
+            .content
+            .get(parent.start as usize..parent.end as usize)
+            .unwrap_or_default()
+            .to_owned();
+        let span = Span {
+            start: 0,
+            end: content.len() as u32,
+            source: (),
+        };
+        let source_id =
+            self.new_concatenated_source_id(path, content, vec![Segment::new(span, parent)])?;
+        self.get_source_mut(source_id)?.metadata_mut().origin = crate::SourceOrigin::Virtual;
+        Ok(source_id)
+    }
+
+    /// Adds a source made of sections of other sources, for example the output of a preprocessor
+    /// which splices in `#include`d files. Spans in a [Segment] are converted to positions in the
+    /// source it was copied from (by [FileSystem::resolve_original_span]), so diagnostics and
+    /// source maps refer to that source. Spans in `content` outside of segments are kept.
+    ///
+    /// `segments` must be sorted, not overlap and each be the same length as its original. Segments
+    /// are not followed after the source they were copied from changes. Its
+    /// [origin](SourceMetadata::origin) is [SourceOrigin::Generated](crate::SourceOrigin::Generated)
+    pub fn new_concatenated_source_id(
+        &mut self,
+        path: PathBuf,
+        content: String,
+        mut segments: Vec<Segment>,
+    ) -> Result<SourceId, Error> {
+        let mut last_end = 0;
+        for (index, segment) in segments.iter_mut().enumerate() {
+            let Segment { span, original, .. } = *segment;
+            let source = self.get_source(original.source)?;
+            segment.original_revision = source.revision;
+            source.check_position(original.end as usize)?;
+            source.check_position(original.start as usize)?;
+            check_position(&content, span.end as usize)?;
+            check_position(&content, span.start as usize)?;
+
+            let reason = if span.start > span.end || original.start > original.end {
+                "starts after it ends"
+            } else if span.start < last_end {
+                "not sorted or overlaps previous segment"
+            } else if span.end - span.start != original.end - original.start {
+                "length is not the same as the original"
+            } else {
+                last_end = span.end;
+                continue;
+            };
+            return Err(Error::InvalidSegment { index, reason });
+        }
+
+        let source_id = self.new_source_id(path, content);
+        let source = self.get_source_mut(source_id)?;
+        source.segments = segments;
+        source.metadata_mut().origin = crate::SourceOrigin::Generated;
+        Ok(source_id)
+    }
+
     pub fn update_file(&mut self, id: SourceId, content: String) {
-        let item = &mut self.sources[id.0 as usize - 1];
-        item.line_starts = LineStarts::new(&content);
-        item.content = content;
+        let line_terminators = self.line_terminators;
+        let revision = self.next_revision();
+        let item = self
+            .get_source_mut(id)
+            .unwrap_or_else(|err| panic!("{}", err));
+        item.line_starts = LineStarts::new_with_line_terminators(&content, line_terminators);
+        item.content = Arc::new(content);
+        item.content_changed(revision);
     }
 
     /// Returns the OLD and NEW length of the file's content
     pub fn append_to_file(&mut self, id: SourceId, content: &str) -> (usize, usize) {
-        let existing = &mut self.sources[id.0 as usize - 1];
+        let revision = self.next_revision();
+        let existing = self
+            .get_source_mut(id)
+            .unwrap_or_else(|err| panic!("{}", err));
         let old_length = existing.content.len();
-        existing.line_starts.append(old_length, content);
-        existing.content.push_str(content);
+        existing
+            .line_starts
+            .append_after(&existing.content, content);
+        Arc::make_mut(&mut existing.content).push_str(content);
+        existing.content_changed(revision);
         (old_length, existing.content.len())
     }
+
+    /// Replaces `range` of the content with `new_text`. Only rescans `new_text` for lines rather
+    /// than the whole source (see [LineStarts::replace])
+    pub fn replace_in_file(
+        &mut self,
+        id: SourceId,
+        range: Range<usize>,
+        new_text: &str,
+    ) -> Result<(), Error> {
+        let existing = self.get_source(id)?;
+        existing.check_position(range.start)?;
+        existing.check_position(range.end)?;
+        if range.start > range.end {
+            return Err(Error::InvalidRange {
+                start: range.start,
+                end: range.end,
+            });
+        }
+        let revision = self.next_revision();
+        let existing = self.get_source_mut(id)?;
+        existing
+            .line_starts
+            .replace_in(&existing.content, range.clone(), new_text);
+        Arc::make_mut(&mut existing.content).replace_range(range, new_text);
+        existing.content_changed(revision);
+        Ok(())
+    }
+
+    /// Removes a source, returning it. Its [SourceId] may be reused with a newer generation, so
+    /// using it after is detected as [Error::StaleSourceId]
+    pub fn remove_file(&mut self, id: SourceId) -> Result<Source, Error> {
+        self.get_source(id)?;
+        let slot = &mut self.sources[id.position()];
+        let source = slot.source.take().unwrap();
+        // Generations are not wrapped around, so instead the slot is not reused
+        if slot.generation < u16::MAX {
+            self.removed.push(id.position());
+        }
+        self.mappings.remove_path(&source.path, id);
+        let revision = self.next_revision();
+        self.removals.push((revision, id));
+        Ok(source)
+    }
+
+    /// The current revision. Compare with [Source::revision] or pass to
+    /// [MapFileStore::changed_since] to find sources which have changed since
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Ids of sources added or changed after `revision`. Does not include removed sources, see
+    /// [MapFileStore::removed_since]
+    pub fn changed_since(&self, revision: u64) -> impl Iterator<Item = SourceId> + '_ {
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(move |(_, slot)| {
+                slot.source
+                    .as_ref()
+                    .is_some_and(|source| source.revision > revision)
+            })
+            .map(move |(position, slot)| {
+                SourceId::from_index(position + 1, slot.generation, self.tag)
+            })
+    }
+
+    /// Ids of sources removed after `revision` (including by [MapFileStore::clear]). These are
+    /// stale, so can only be used to drop data derived from them.
+    ///
+    /// Incomplete if `revision` is before that passed to [MapFileStore::forget_removals_before]
+    pub fn removed_since(&self, revision: u64) -> impl Iterator<Item = SourceId> + '_ {
+        let start = self
+            .removals
+            .partition_point(|(removed_at, _)| *removed_at <= revision);
+        self.removals[start..]
+            .iter()
+            .map(|(_, source_id)| *source_id)
+    }
+
+    /// Frees the record of sources removed at or before `revision`, which otherwise grows with
+    /// every removal. Call with the oldest revision still passed to [MapFileStore::removed_since]
+    pub fn forget_removals_before(&mut self, revision: u64) {
+        let end = self
+            .removals
+            .partition_point(|(removed_at, _)| *removed_at <= revision);
+        self.removals.drain(..end);
+    }
+
+    fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        self.revision
+    }
+
+    /// Removes all sources. Existing [SourceId]s become stale, as with [MapFileStore::remove_file]
+    pub fn clear(&mut self) {
+        let source_ids = self.source_ids().collect::<Vec<_>>();
+        for source_id in source_ids {
+            self.remove_file(source_id).unwrap();
+        }
+        self.compact();
+    }
+
+    /// Ids of all sources (which have not been removed)
+    pub fn source_ids(&self) -> impl Iterator<Item = SourceId> + '_ {
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.source.is_some())
+            .map(move |(position, slot)| {
+                SourceId::from_index(position + 1, slot.generation, self.tag)
+            })
+    }
+
+    /// Frees memory from removed sources. Removed sources at the end of the store are dropped
+    /// entirely. Returns the number dropped
+    pub fn compact(&mut self) -> usize {
+        let mut dropped = 0;
+        while let Some(Slot {
+            source: None,
+            generation,
+        }) = self.sources.last()
+        {
+            if *generation == u16::MAX {
+                break;
+            }
+            let slot = self.sources.pop().unwrap();
+            // Sources later added at this position must not have an id which was used before
+            self.next_generation = self.next_generation.max(slot.generation.saturating_add(1));
+            dropped += 1;
+        }
+        let length = self.sources.len();
+        self.removed.retain(|position| *position < length);
+        self.sources.shrink_to_fit();
+        self.removed.shrink_to_fit();
+        dropped
+    }
 }
 
 impl MapFileStore<WithPathMap> {
     /// Updates an **existing** entry
-    ///
-    /// TODO partial updates
     pub fn update_file_at_path(&mut self, path: &Path, content: String) {
-        self.update_file(self.mappings.0[path], content);
+        self.update_file(self.expect_source_at_path(path), content);
+    }
+
+    /// Partial update of an **existing** entry. See [MapFileStore::replace_in_file]
+    pub fn replace_in_file_at_path(
+        &mut self,
+        path: &Path,
+        range: Range<usize>,
+        new_text: &str,
+    ) -> Result<(), Error> {
+        let id = self
+            .get_source_at_path(path)
+            .ok_or_else(|| Error::NoSourceAtPath(path.to_path_buf()))?;
+        self.replace_in_file(id, range, new_text)
     }
 
     /// Returns a possible [SourceId] for a path
     pub fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
-        self.mappings.0.get(path).copied()
+        self.mappings.get(path)
     }
 
-    /// Either a rename or move. **Must already exist**
+    fn expect_source_at_path(&self, path: &Path) -> SourceId {
+        self.get_source_at_path(path)
+            .unwrap_or_else(|| panic!("no source at {}", path.display()))
+    }
+
+    /// Either a rename or move. **Must already exist**. A source already at `to` is removed. See
+    /// [MapFileStore::rename_file]
     pub fn change_file_path(&mut self, from: &Path, to: PathBuf) {
-        let id = self.mappings.0[from];
-        self.sources[id.0 as usize - 1].path = to;
-        self.mappings.0.remove(from);
-        self.mappings.0.insert(from.to_path_buf(), id);
+        self.rename_file(from, to, RenameConflict::Overwrite)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Moves the source at `from` to `to`, keeping its [SourceId]. Returns whether it was moved
+    /// (it is not if there is a source at `to` and `on_conflict` is [RenameConflict::Skip])
+    pub fn rename_file(
+        &mut self,
+        from: &Path,
+        to: PathBuf,
+        on_conflict: RenameConflict,
+    ) -> Result<bool, Error> {
+        let id = self
+            .get_source_at_path(from)
+            .ok_or_else(|| Error::NoSourceAtPath(from.to_path_buf()))?;
+        let renamed = self.rename_sources(vec![(id, to)], on_conflict)?;
+        Ok(!renamed.is_empty())
+    }
+
+    /// Moves every source under the directory `from` to under `to`. Returns the sources moved.
+    /// Nothing is moved if there is an error. `from` can be `.`, which moves every relative path
+    /// (other than those starting with `..`)
+    pub fn rename_directory(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        on_conflict: RenameConflict,
+    ) -> Result<Vec<SourceId>, Error> {
+        let from = crate::paths::normalise(from);
+        let is_current = from == Path::new(".");
+        let (from_key, depth) = if is_current {
+            (PathBuf::new(), 0)
+        } else {
+            (self.mappings.key(&from), from.components().count())
+        };
+        let renames = self
+            .source_ids()
+            .filter_map(|id| {
+                let path = crate::paths::normalise(&self.get_source(id).ok()?.path);
+                let is_outside_current = is_current && (path.has_root() || path.starts_with(".."));
+                if self.mappings.key(&path).starts_with(&from_key) && !is_outside_current {
+                    Some((
+                        id,
+                        to.join(path.components().skip(depth).collect::<PathBuf>()),
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.rename_sources(renames, on_conflict)
+    }
+
+    fn rename_sources(
+        &mut self,
+        renames: Vec<(SourceId, PathBuf)>,
+        on_conflict: RenameConflict,
+    ) -> Result<Vec<SourceId>, Error> {
+        // Every conflict is found before anything is changed. Skipped sources keep their paths,
+        // which can conflict with other renames, so this repeats until nothing more is skipped
+        let mut accepted = renames;
+        let replaced = loop {
+            let moving = accepted.iter().map(|(id, _)| *id).collect::<HashSet<_>>();
+            let mut replaced = Vec::new();
+            let mut next = Vec::with_capacity(accepted.len());
+            for (id, to) in &accepted {
+                match self.get_source_at_path(to) {
+                    // Not a conflict if the existing source is also being moved
+                    Some(existing) if existing != *id && !moving.contains(&existing) => {
+                        match on_conflict {
+                            RenameConflict::Error => {
+                                return Err(Error::PathAlreadyExists(to.clone()))
+                            }
+                            RenameConflict::Overwrite if !replaced.contains(&existing) => {
+                                replaced.push(existing)
+                            }
+                            RenameConflict::Overwrite => {}
+                            RenameConflict::Skip => continue,
+                        }
+                    }
+                    _ => {}
+                }
+                next.push((*id, to.clone()));
+            }
+            if next.len() == accepted.len() {
+                break replaced;
+            }
+            accepted = next;
+        };
+
+        for existing in replaced {
+            self.remove_file(existing)?;
+        }
+        // All old paths are removed first, as a source can be moved to the old path of another
+        for (id, _) in &accepted {
+            let path = self.get_source(*id)?.path.clone();
+            self.mappings.remove_path(&path, *id);
+        }
+        let mut renamed = Vec::with_capacity(accepted.len());
+        for (id, to) in accepted {
+            self.get_source_mut(id)?.path = to.clone();
+            self.mappings.set_path(to, id);
+            renamed.push(id);
+        }
+        Ok(renamed)
     }
 
     pub fn create_or_update_file_at_path(&mut self, path: &Path, content: String) {
-        if let Some(existing_id) = self.mappings.0.get(path) {
-            self.update_file(*existing_id, content);
+        if let Some(existing_id) = self.get_source_at_path(path) {
+            self.update_file(existing_id, content);
         } else {
             self.new_source_id(path.to_path_buf(), content);
         }
     }
 }
 
+/// Paths registered without content are empty sources
+impl FileSystemWithPaths for MapFileStore<WithPathMap> {
+    fn get_or_create_source_id(&mut self, path: &Path) -> SourceId {
+        match self.get_source_at_path(path) {
+            Some(existing) => existing,
+            None => self.new_source_id(path.to_path_buf(), String::new()),
+        }
+    }
+
+    fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
+        MapFileStore::get_source_at_path(self, path)
+    }
+}
+
 #[cfg(feature = "codespan-reporting")]
 pub struct CodeSpanStore<'a, T: FileSystem>(&'a T);
 
+/// Shared content of a source for [CodeSpanStore]
+#[cfg(feature = "codespan-reporting")]
+#[derive(Clone, Debug)]
+pub struct CodeSpanSource(pub Arc<String>);
+
+#[cfg(feature = "codespan-reporting")]
+impl AsRef<str> for CodeSpanSource {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
 #[cfg(feature = "codespan-reporting")]
 impl<'a, T: FileSystem> codespan_reporting::files::Files<'a> for CodeSpanStore<'a, T> {
     type FileId = SourceId;
     type Name = String;
-    // TODO should just be &str
-    type Source = String;
+    type Source = CodeSpanSource;
 
+    /// For embedded sources, the name of the source it is embedded in
     fn name(&'a self, id: Self::FileId) -> Result<Self::Name, codespan_reporting::files::Error> {
-        Ok(self.0.get_file_path(id).display().to_string())
+        let (root, _) = self.embedded_root(id)?;
+        self.0
+            .try_get_source_by_id(root, |source| source.path.display().to_string())
+            .map_err(Into::into)
+    }
+
+    /// For embedded sources, the line number in the source it is embedded in
+    fn line_number(
+        &'a self,
+        id: Self::FileId,
+        line_index: usize,
+    ) -> Result<usize, codespan_reporting::files::Error> {
+        let (line_offset, _) = self.embedded_offset(id)?;
+        Ok(line_offset + line_index + 1)
+    }
+
+    /// For embedded sources, the column number in the source it is embedded in
+    fn column_number(
+        &'a self,
+        id: Self::FileId,
+        line_index: usize,
+        byte_index: usize,
+    ) -> Result<usize, codespan_reporting::files::Error> {
+        let (_, column_offset) = self.embedded_offset(id)?;
+        let source = self.source(id)?;
+        let line_range = self.line_range(id, line_index)?;
+        let column_index =
+            codespan_reporting::files::column_index(source.as_ref(), line_range, byte_index);
+        // Only the first line of an embedded source does not start at the start of a line
+        let column_offset = if line_index == 0 { column_offset } else { 0 };
+        Ok(column_offset + column_index + 1)
     }
 
     fn source(
         &'a self,
         id: Self::FileId,
     ) -> Result<Self::Source, codespan_reporting::files::Error> {
-        Ok(self.0.get_file_content(id))
+        self.0
+            .try_get_source_by_id(id, |source| CodeSpanSource(source.content.clone()))
+            .map_err(Into::into)
     }
 
-    // Implementation copied from codespan codebase
     fn line_index(
         &'a self,
         id: Self::FileId,
         byte_index: usize,
     ) -> Result<usize, codespan_reporting::files::Error> {
-        Ok(self.0.get_source_by_id(id, |source| {
-            source
-                .line_starts
-                .0
-                .binary_search(&byte_index)
-                .unwrap_or_else(|next_line| next_line - 1)
-        }))
+        self.0
+            .try_get_source_by_id(id, |source| {
+                source.line_starts.get_line_pos_is_on(byte_index)
+            })
+            .map_err(Into::into)
     }
 
     fn line_range(
@@ -256,34 +1289,766 @@ impl<'a, T: FileSystem> codespan_reporting::files::Files<'a> for CodeSpanStore<'
         line_index: usize,
     ) -> Result<std::ops::Range<usize>, codespan_reporting::files::Error> {
         // Implementation copied from codespan codebase
-        self.0.get_source_by_id(id, |source| {
+        self.0.try_get_source_by_id(id, |source| {
             // Copied from codespan-reporting
             fn line_start(
-                line_starts: &[usize],
+                line_starts: &LineStarts,
                 line_index: usize,
                 source_len: usize,
             ) -> Result<usize, codespan_reporting::files::Error> {
                 use std::cmp::Ordering;
 
-                match line_index.cmp(&line_starts.len()) {
+                match line_index.cmp(&line_starts.line_count()) {
                     Ordering::Less => Ok(line_starts
-                        .get(line_index)
-                        .cloned()
+                        .get_line_start(line_index)
                         .expect("failed despite previous check")),
                     Ordering::Equal => Ok(source_len),
                     Ordering::Greater => Err(codespan_reporting::files::Error::LineTooLarge {
                         given: line_index,
-                        max: line_starts.len() - 1,
+                        max: line_starts.line_count() - 1,
                     }),
                 }
             }
 
-            line_start(&source.line_starts.0, line_index, source.content.len()).and_then(
+            line_start(&source.line_starts, line_index, source.content.len()).and_then(
                 |prev_line_start| {
-                    line_start(&source.line_starts.0, line_index + 1, source.content.len())
+                    line_start(&source.line_starts, line_index + 1, source.content.len())
                         .map(|next_line_start| prev_line_start..next_line_start)
                 },
             )
+        })?
+    }
+}
+
+#[cfg(feature = "codespan-reporting")]
+impl<T: FileSystem> CodeSpanStore<'_, T> {
+    /// Label for `span`, which is moved to the source it was copied from if it is in a
+    /// concatenated source (see [MapFileStore::new_concatenated_source_id]). Unlike embedded
+    /// sources, the [codespan_reporting::files::Files] methods cannot do this as a concatenated
+    /// source can be from several sources
+    pub fn label(
+        &self,
+        style: codespan_reporting::diagnostic::LabelStyle,
+        span: SpanWithSource,
+    ) -> codespan_reporting::diagnostic::Label<SourceId> {
+        self.try_label(style, span)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [CodeSpanStore::label] but returns an error rather than panicking if the source
+    /// does not exist
+    pub fn try_label(
+        &self,
+        style: codespan_reporting::diagnostic::LabelStyle,
+        span: SpanWithSource,
+    ) -> Result<codespan_reporting::diagnostic::Label<SourceId>, Error> {
+        let span = self.0.try_resolve_original_span(span)?;
+        Ok(codespan_reporting::diagnostic::Label::new(
+            style,
+            span.source,
+            span.start as usize..span.end as usize,
+        ))
+    }
+
+    /// The outermost source `id` is embedded in and the position of `id` in it. `id` for other
+    /// sources
+    fn embedded_root(&self, id: SourceId) -> Result<(SourceId, u32), Error> {
+        let start = SpanWithSource {
+            start: 0,
+            end: 0,
+            source: id,
+        };
+        let root = follow_segments(self.0, start, true)?;
+        Ok((root.source, root.start))
+    }
+
+    /// Line and column (in characters) of the start of an embedded source in the source it is
+    /// embedded in. Zero for other sources
+    fn embedded_offset(&self, id: SourceId) -> Result<(usize, usize), Error> {
+        let (root, offset) = self.embedded_root(id)?;
+        if root == id {
+            return Ok((0, 0));
+        }
+        self.0.try_get_source_by_id(root, |source| {
+            let (line, column) =
+                source.get_line_and_column::<crate::encodings::Utf8>(offset as usize)?;
+            Ok((line as usize, column as usize))
+        })?
+    }
+}
+
+#[cfg(feature = "codespan-reporting")]
+impl From<Error> for codespan_reporting::files::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::NullSourceId
+            | Error::UnknownSourceId(_)
+            | Error::StaleSourceId(_)
+            | Error::ForeignSourceId(_)
+            | Error::NoSourceAtPath(_) => codespan_reporting::files::Error::FileMissing,
+            Error::PositionOutOfBounds { position, length } => {
+                codespan_reporting::files::Error::IndexTooLarge {
+                    given: position,
+                    max: length,
+                }
+            }
+            Error::NotOnCharBoundary { position } => {
+                codespan_reporting::files::Error::InvalidCharBoundary { given: position }
+            }
+            Error::InvalidRange { start, end } => codespan_reporting::files::Error::IndexTooLarge {
+                given: start,
+                max: end,
+            },
+            Error::OverlappingSpans { .. } => {
+                codespan_reporting::files::Error::Io(std::io::ErrorKind::InvalidInput.into())
+            }
+            Error::LineOutOfBounds { line, line_count } => {
+                codespan_reporting::files::Error::LineTooLarge {
+                    given: line,
+                    max: line_count - 1,
+                }
+            }
+            Error::Read { kind, .. } => codespan_reporting::files::Error::Io(kind.into()),
+            Error::PathAlreadyExists(_) => {
+                codespan_reporting::files::Error::Io(std::io::ErrorKind::AlreadyExists.into())
+            }
+            Error::ReadOnly(_) => {
+                codespan_reporting::files::Error::Io(std::io::ErrorKind::PermissionDenied.into())
+            }
+            Error::TooManySources { .. } => {
+                codespan_reporting::files::Error::Io(std::io::ErrorKind::Other.into())
+            }
+            Error::InvalidSnapshot { .. }
+            | Error::InvalidSegment { .. }
+            | Error::InvalidEscape { .. } => {
+                codespan_reporting::files::Error::Io(std::io::ErrorKind::InvalidData.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A [FileSystem] which only implements the methods required before
+    /// [FileSystem::try_get_source_by_id] was added
+    struct SingleSource(Source);
+
+    impl FileSystem for SingleSource {
+        fn new_source_id_with_line_starts(
+            &mut self,
+            _path: PathBuf,
+            _content: String,
+        ) -> (SourceId, LineStarts) {
+            unimplemented!()
+        }
+
+        fn get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+            &self,
+            _source_id: SourceId,
+            f: F,
+        ) -> T {
+            f(&self.0)
+        }
+    }
+
+    #[test]
+    fn only_get_source_by_id() {
+        let content = "let a;\nlet b;";
+        let fs = SingleSource(Source::new(
+            "a.ts".into(),
+            Arc::new(content.to_owned()),
+            LineStarts::new(content),
+        ));
+        let span = SpanWithSource {
+            start: 11,
+            end: 12,
+            source: SourceId::NULL,
+        };
+        let line_column = span
+            .try_into_line_column_span::<crate::encodings::Utf8>(&fs)
+            .unwrap();
+        assert_eq!((line_column.line_start, line_column.column_start), (1, 4));
+    }
+
+    #[test]
+    fn shared_content() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let a = fs.new_source_id("a.ts".into(), "let a = 1;".into());
+
+        let shared = fs.get_shared_file_content(a);
+        assert!(Arc::ptr_eq(&shared, &fs.get_shared_file_content(a)));
+
+        fs.replace_in_file(a, 8..9, "2\nlet b = 3").unwrap();
+        assert_eq!(&*shared, "let a = 1;");
+        drop(shared);
+
+        // Not shared, so edited in place
+        let before = fs.get_source_by_id(a, |source| Arc::as_ptr(&source.content));
+        fs.append_to_file(a, "\n");
+        fs.replace_in_file(a, 0..3, "var").unwrap();
+        assert_eq!(
+            fs.get_source_by_id(a, |source| Arc::as_ptr(&source.content)),
+            before
+        );
+        assert_eq!(&*fs.get_file_content(a), "var a = 2\nlet b = 3;\n");
+    }
+
+    #[test]
+    fn invalid_replacements() {
+        let mut fs = MapFileStore::<WithPathMap>::default();
+        let a = fs.new_source_id("a.ts".into(), "let a = 1;".into());
+
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..4;
+        assert_eq!(
+            fs.replace_in_file(a, reversed, "x"),
+            Err(Error::InvalidRange { start: 5, end: 4 })
+        );
+        assert_eq!(
+            fs.replace_in_file_at_path(Path::new("b.ts"), 0..0, "x"),
+            Err(Error::NoSourceAtPath("b.ts".into()))
+        );
+        assert_eq!(fs.get_file_content(a), "let a = 1;");
+    }
+
+    #[test]
+    fn change_detection() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let a = fs.new_source_id("a.ts".into(), "let a = 1;".into());
+        let b = fs.new_source_id("b.ts".into(), "let b = 1;".into());
+        let checkpoint = fs.revision();
+        assert_eq!(fs.changed_since(0).collect::<Vec<_>>(), [a, b]);
+        assert_eq!(fs.changed_since(checkpoint).count(), 0);
+
+        let hash = fs.get_source_by_id(b, Source::content_hash);
+        fs.append_to_file(b, "\n");
+        assert_ne!(fs.get_source_by_id(b, Source::content_hash), hash);
+        assert_eq!(fs.changed_since(checkpoint).collect::<Vec<_>>(), [b]);
+
+        // Same content has the same hash
+        fs.replace_in_file(b, 10..11, "").unwrap();
+        assert_eq!(fs.get_source_by_id(b, Source::content_hash), hash);
+        assert_eq!(fs.removed_since(checkpoint).count(), 0);
+        // Hashes are persisted so must not change
+        assert_eq!(content_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fs.get_source_by_id(b, Source::revision), fs.revision());
+
+        let before_removal = fs.revision();
+        fs.remove_file(a).unwrap();
+        assert_eq!(fs.removed_since(checkpoint).collect::<Vec<_>>(), [a]);
+        assert_eq!(fs.removed_since(fs.revision()).count(), 0);
+        // Reusing the slot does not hide the removal
+        let c = fs.new_source_id("c.ts".into(), String::new());
+        assert_eq!(fs.changed_since(before_removal).collect::<Vec<_>>(), [c]);
+        assert_eq!(fs.removed_since(before_removal).collect::<Vec<_>>(), [a]);
+
+        let after_first_removal = fs.revision();
+        fs.remove_file(b).unwrap();
+        fs.remove_file(c).unwrap();
+        fs.compact();
+        assert_eq!(fs.removed_since(0).count(), 3);
+        fs.forget_removals_before(after_first_removal);
+        assert_eq!(
+            fs.removed_since(after_first_removal).collect::<Vec<_>>(),
+            [b, c]
+        );
+        // Incomplete for earlier revisions
+        assert_eq!(fs.removed_since(0).collect::<Vec<_>>(), [b, c]);
+        fs.forget_removals_before(fs.revision());
+        assert_eq!(fs.removed_since(0).count(), 0);
+    }
+
+    #[test]
+    fn normalised_paths() {
+        let mut fs = MapFileStore::<WithPathMap>::default();
+        let a = fs.new_source_id("./src/a.ts".into(), "a".into());
+        assert_eq!(fs.get_source_at_path(Path::new("src/a.ts")), Some(a));
+        assert_eq!(fs.get_source_at_path(Path::new("src\\b/../a.ts")), Some(a));
+        assert_eq!(fs.get_source_at_path(Path::new("src/A.ts")), None);
+        // The path is kept as given
+        assert_eq!(fs.get_file_path(a), Path::new("./src/a.ts"));
+
+        let mut fs = MapFileStore::new_with_path_map(
+            WithPathMap::case_insensitive(),
+            LineTerminators::default(),
+        );
+        let a = fs.new_source_id("Src/A.ts".into(), "a".into());
+        assert_eq!(fs.get_source_at_path(Path::new("./src/a.TS")), Some(a));
+    }
+
+    #[test]
+    fn renaming() {
+        let mut fs = MapFileStore::<WithPathMap>::default();
+        let a = fs.new_source_id("src/a.ts".into(), "a".into());
+        let b = fs.new_source_id("src/utils/b.ts".into(), "b".into());
+        let c = fs.new_source_id("c.ts".into(), "c".into());
+        let other = fs.new_source_id("src2/a.ts".into(), "a".into());
+
+        fs.change_file_path(Path::new("c.ts"), "d.ts".into());
+        assert_eq!(fs.get_source_at_path(Path::new("d.ts")), Some(c));
+        assert_eq!(fs.get_source_at_path(Path::new("c.ts")), None);
+        assert_eq!(fs.get_file_path(c), Path::new("d.ts"));
+
+        assert_eq!(
+            fs.rename_file(Path::new("d.ts"), "src/a.ts".into(), RenameConflict::Error),
+            Err(Error::PathAlreadyExists("src/a.ts".into()))
+        );
+        assert_eq!(
+            fs.rename_file(Path::new("d.ts"), "src/a.ts".into(), RenameConflict::Skip),
+            Ok(false)
+        );
+        assert_eq!(
+            fs.rename_file(Path::new("c.ts"), "e.ts".into(), RenameConflict::Error),
+            Err(Error::NoSourceAtPath("c.ts".into()))
+        );
+
+        assert_eq!(
+            fs.rename_directory(Path::new("./src"), Path::new("lib"), RenameConflict::Error),
+            Ok(vec![a, b])
+        );
+        assert_eq!(fs.get_source_at_path(Path::new("lib/a.ts")), Some(a));
+        assert_eq!(fs.get_source_at_path(Path::new("lib/utils/b.ts")), Some(b));
+        assert_eq!(fs.get_source_at_path(Path::new("src/a.ts")), None);
+        assert_eq!(fs.get_source_at_path(Path::new("src2/a.ts")), Some(other));
+
+        // Replaces the existing source
+        assert_eq!(
+            fs.rename_directory(
+                Path::new("src2"),
+                Path::new("lib"),
+                RenameConflict::Overwrite
+            ),
+            Ok(vec![other])
+        );
+        assert_eq!(fs.get_source_at_path(Path::new("lib/a.ts")), Some(other));
+        assert_eq!(
+            fs.try_get_source_by_id(a, |_| ()),
+            Err(Error::StaleSourceId(a))
+        );
+
+        // Moves every relative path
+        let absolute = fs.new_source_id("/abs.ts".into(), String::new());
+        assert_eq!(
+            fs.rename_directory(Path::new("."), Path::new("out"), RenameConflict::Error),
+            Ok(vec![b, c, other])
+        );
+        assert_eq!(
+            fs.get_source_at_path(Path::new("out/lib/a.ts")),
+            Some(other)
+        );
+        assert_eq!(fs.get_source_at_path(Path::new("out/d.ts")), Some(c));
+        assert_eq!(fs.get_source_at_path(Path::new("/abs.ts")), Some(absolute));
+    }
+
+    #[test]
+    fn renaming_with_conflicts() {
+        let mut fs = MapFileStore::<WithPathMap>::default();
+        let a = fs.new_source_id("src/a.ts".into(), "a".into());
+        let b = fs.new_source_id("src/b.ts".into(), "b".into());
+        let existing = fs.new_source_id("lib/a.ts".into(), String::new());
+        let blocker = fs.new_source_id("lib/b.ts".into(), String::new());
+        fs.rename_file(
+            Path::new("lib/b.ts"),
+            "src/c.ts".into(),
+            RenameConflict::Error,
+        )
+        .unwrap();
+
+        // Nothing changes if a later rename conflicts
+        fs.new_source_id("lib/b.ts".into(), String::new());
+        assert_eq!(
+            fs.rename_directory(Path::new("src"), Path::new("lib"), RenameConflict::Error),
+            Err(Error::PathAlreadyExists("lib/a.ts".into()))
+        );
+        assert_eq!(fs.get_source_at_path(Path::new("src/b.ts")), Some(b));
+
+        // `lib/a.ts` and `lib/b.ts` are replaced, `src/c.ts` moves to `lib/c.ts`
+        assert_eq!(
+            fs.rename_directory(
+                Path::new("src"),
+                Path::new("lib"),
+                RenameConflict::Overwrite
+            ),
+            Ok(vec![a, b, blocker])
+        );
+        assert_eq!(fs.source_ids().count(), 3);
+        assert!(fs.try_get_source_by_id(existing, |_| ()).is_err());
+        assert_eq!(fs.get_source_at_path(Path::new("lib/a.ts")), Some(a));
+    }
+
+    #[test]
+    fn sources_from_bytes() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let a = fs.new_source_id_from_bytes("a.ts".into(), b"\xFF\xFEa\x00=\x001\x00", None);
+        assert_eq!(fs.get_file_content(a), "a=1");
+        let original_offset = |fs: &MapFileStore<NoPathMap>| {
+            fs.get_source_by_id(a, |source| {
+                source
+                    .decoding()
+                    .map(|decoding| decoding.to_original_offset(2))
+            })
+        };
+        assert_eq!(original_offset(&fs), Some(6));
+
+        let restored = MapFileStore::<NoPathMap>::from_snapshot(&fs.to_snapshot()).unwrap();
+        assert_eq!(original_offset(&restored), Some(6));
+
+        fs.append_to_file(a, ";");
+        assert_eq!(original_offset(&fs), None);
+    }
+
+    #[test]
+    fn metadata() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let a = fs.new_source_id("a.ts".into(), "let a;".into());
+        assert_eq!(fs.get_file_metadata(a).origin, crate::SourceOrigin::OnDisk);
+
+        fs.update_metadata(a, |metadata| {
+            metadata.language_id = Some("typescript".into());
+            metadata.version = Some(1);
+            metadata.set_user_data(42u32);
         })
+        .unwrap();
+        fs.update_file(a, "let b;".into());
+
+        let metadata = fs.get_file_metadata(a);
+        assert_eq!(metadata.language_id.as_deref(), Some("typescript"));
+        assert_eq!(metadata.version, Some(1));
+        assert_eq!(metadata.user_data::<u32>(), Some(&42));
+        assert_eq!(metadata.user_data::<i64>(), None);
+
+        let restored = MapFileStore::<NoPathMap>::from_snapshot(&fs.to_snapshot()).unwrap();
+        let metadata = restored.get_file_metadata(a);
+        assert_eq!(metadata.version, Some(1));
+        assert!(metadata.user_data.is_none());
+    }
+
+    #[test]
+    fn embedded_sources() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let html = fs.new_source_id(
+            "index.html".into(),
+            "<p>\n<script>let a;\nlet b;</script>".into(),
+        );
+        let script = fs
+            .new_embedded_source_id(
+                "index.html.js".into(),
+                SpanWithSource {
+                    start: 12,
+                    end: 25,
+                    source: html,
+                },
+            )
+            .unwrap();
+        assert_eq!(fs.get_file_content(script), "let a;\nlet b;");
+        assert_eq!(
+            fs.get_file_metadata(script).origin,
+            crate::SourceOrigin::Virtual
+        );
+
+        // `b` in `let b;`
+        let b = SpanWithSource {
+            start: 11,
+            end: 12,
+            source: script,
+        };
+        assert_eq!(
+            fs.resolve_original_span(b),
+            SpanWithSource {
+                start: 23,
+                end: 24,
+                source: html
+            }
+        );
+        let line_column = b.into_line_column_span::<crate::encodings::Utf8>(&fs);
+        assert_eq!(
+            (
+                line_column.line_start,
+                line_column.column_start,
+                line_column.source
+            ),
+            (2, 4, html)
+        );
+
+        let mut builder = crate::SourceMapBuilder::new();
+        builder.add_mapping(&b, 0);
+        let source_map = builder.build(&fs);
+        assert_eq!(source_map.sources, vec![html]);
+        assert_eq!(source_map.mappings, "AAEI");
+
+        #[cfg(feature = "codespan-reporting")]
+        {
+            use codespan_reporting::files::Files;
+            let store = fs.into_code_span_store();
+            assert_eq!(store.name(script).unwrap(), "index.html");
+            assert_eq!(store.location(script, 4).unwrap().line_number, 2);
+            assert_eq!(store.location(script, 4).unwrap().column_number, 13);
+            assert_eq!(store.location(script, 11).unwrap().column_number, 5);
+        }
+
+        assert_eq!(
+            fs.new_embedded_source_id(
+                "a.js".into(),
+                SpanWithSource {
+                    start: 12,
+                    end: 100,
+                    source: html,
+                },
+            ),
+            Err(Error::PositionOutOfBounds {
+                position: 100,
+                length: 34
+            })
+        );
+
+        // After the parent changes the script is no longer at the same position in it
+        fs.replace_in_file(html, 0..4, "").unwrap();
+        assert_eq!(fs.resolve_original_span(b), b);
+        #[cfg(feature = "codespan-reporting")]
+        {
+            use codespan_reporting::files::Files;
+            let store = fs.into_code_span_store();
+            assert_eq!(store.name(script).unwrap(), "index.html.js");
+        }
+    }
+
+    #[test]
+    fn cyclic_segments() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let a = fs.new_source_id("a.txt".into(), "a".into());
+        let b = fs
+            .new_embedded_source_id(
+                "b.txt".into(),
+                SpanWithSource {
+                    start: 0,
+                    end: 1,
+                    source: a,
+                },
+            )
+            .unwrap();
+        // Only possible by changing the store directly
+        let b_revision = fs.get_source_by_id(b, Source::revision);
+        fs.get_source_mut(a).unwrap().segments = vec![Segment {
+            original_revision: b_revision,
+            ..Segment::new(
+                Span {
+                    start: 0,
+                    end: 1,
+                    source: (),
+                },
+                SpanWithSource {
+                    start: 0,
+                    end: 1,
+                    source: b,
+                },
+            )
+        }];
+        assert_eq!(
+            fs.try_resolve_original_span(SpanWithSource {
+                start: 0,
+                end: 1,
+                source: a
+            }),
+            Err(Error::InvalidSegment {
+                index: 0,
+                reason: "nested too deeply or cyclic"
+            })
+        );
+    }
+
+    #[test]
+    fn concatenated_sources() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let header = fs.new_source_id("a.h".into(), "int a;\n".into());
+        let main = fs.new_source_id("main.c".into(), "#include \"a.h\"\nint main;\n".into());
+        let segments = vec![
+            Segment::new(
+                Span {
+                    start: 0,
+                    end: 7,
+                    source: (),
+                },
+                SpanWithSource {
+                    start: 0,
+                    end: 7,
+                    source: header,
+                },
+            ),
+            Segment::new(
+                Span {
+                    start: 7,
+                    end: 17,
+                    source: (),
+                },
+                SpanWithSource {
+                    start: 15,
+                    end: 25,
+                    source: main,
+                },
+            ),
+        ];
+        let preprocessed = fs
+            .new_concatenated_source_id(
+                "main.i".into(),
+                "int a;\nint main;\n".into(),
+                segments.clone(),
+            )
+            .unwrap();
+        assert_eq!(
+            fs.get_file_metadata(preprocessed).origin,
+            crate::SourceOrigin::Generated
+        );
+
+        let a = SpanWithSource {
+            start: 4,
+            end: 5,
+            source: preprocessed,
+        };
+        let main_ident = SpanWithSource {
+            start: 11,
+            end: 15,
+            source: preprocessed,
+        };
+        assert_eq!(
+            fs.resolve_original_span(a),
+            SpanWithSource {
+                start: 4,
+                end: 5,
+                source: header
+            }
+        );
+        let line_column = main_ident.into_line_column_span::<crate::encodings::Utf8>(&fs);
+        assert_eq!(
+            (
+                line_column.line_start,
+                line_column.column_start,
+                line_column.source
+            ),
+            (1, 4, main)
+        );
+
+        let mut builder = crate::SourceMapBuilder::new();
+        builder.add_mapping(&a, 0);
+        builder.add_mapping(&main_ident, 6);
+        let source_map = builder.build(&fs);
+        assert_eq!(source_map.sources, vec![header, main]);
+        assert_eq!(source_map.mappings, "AAAI,MCCA");
+
+        #[cfg(feature = "codespan-reporting")]
+        {
+            use codespan_reporting::diagnostic::LabelStyle;
+            let label = fs
+                .into_code_span_store()
+                .label(LabelStyle::Primary, main_ident);
+            assert_eq!((label.file_id, label.range), (main, 19..23));
+        }
+
+        let restored = MapFileStore::<NoPathMap>::from_snapshot(&fs.to_snapshot()).unwrap();
+        assert_eq!(restored.resolve_original_span(main_ident).source, main);
+
+        // Segments are dropped after changes
+        fs.update_file(preprocessed, "int a;\nint main;\n".into());
+        assert_eq!(fs.resolve_original_span(a), a);
+
+        let mut overlapping = segments;
+        overlapping[1].span.start = 6;
+        overlapping[1].original.start = 14;
+        assert_eq!(
+            fs.new_concatenated_source_id("b.i".into(), "int a;\nint main;\n".into(), overlapping),
+            Err(Error::InvalidSegment {
+                index: 1,
+                reason: "not sorted or overlaps previous segment"
+            })
+        );
+    }
+
+    #[test]
+    fn removing_and_reusing_source_ids() {
+        let mut fs = MapFileStore::<WithPathMap>::default();
+        let a = fs.new_source_id("a.ts".into(), "a".into());
+        let b = fs.new_source_id("b.ts".into(), "b".into());
+
+        assert_eq!(
+            fs.remove_file(a).map(|source| source.content.to_string()),
+            Ok("a".to_owned())
+        );
+        assert_eq!(fs.get_source_at_path(Path::new("a.ts")), None);
+        assert_eq!(
+            fs.try_get_source_by_id(a, |_| ()),
+            Err(Error::StaleSourceId(a))
+        );
+
+        // Reuses the position of `a` but `a` is still stale
+        let c = fs.new_source_id("c.ts".into(), "c".into());
+        assert_ne!(a, c);
+        assert_eq!(fs.get_file_content(c), "c");
+        assert_eq!(
+            fs.try_get_source_by_id(a, |_| ()),
+            Err(Error::StaleSourceId(a))
+        );
+        assert_eq!(fs.source_ids().collect::<Vec<_>>(), vec![c, b]);
+        let span = |source| SpanWithSource {
+            start: 0,
+            end: 1,
+            source,
+        };
+        assert_eq!(format!("{:?}", span(c)), "0..1#1.1");
+        assert_eq!(format!("{:?}", span(b)), "0..1#2");
+
+        fs.remove_file(b).unwrap();
+        assert_eq!(fs.compact(), 1);
+
+        // Added at the position `b` was at
+        let d = fs.new_source_id("d.ts".into(), "d".into());
+        assert_ne!(b, d);
+        assert!(fs.try_get_source_by_id(b, |_| ()).is_err());
+        assert_eq!(fs.get_file_content(d), "d");
+    }
+
+    #[cfg(feature = "source-id-store-tags")]
+    #[test]
+    fn foreign_source_ids() {
+        let mut first = MapFileStore::<NoPathMap>::default();
+        let mut second = MapFileStore::<NoPathMap>::default();
+        let a = first.new_source_id("a.ts".into(), "a".into());
+        second.new_source_id("b.ts".into(), "b".into());
+
+        assert_eq!(
+            second.try_get_source_by_id(a, |_| ()),
+            Err(Error::ForeignSourceId(a))
+        );
+
+        // Restored stores accept ids from the original
+        let restored = MapFileStore::<NoPathMap>::from_snapshot(&first.to_snapshot()).unwrap();
+        assert_eq!(restored.get_file_content(a), "a");
+
+        // But not from stores created after
+        let mut third = MapFileStore::<NoPathMap>::default();
+        let c = third.new_source_id("c.ts".into(), "c".into());
+        assert_eq!(
+            restored.try_get_source_by_id(c, |_| ()),
+            Err(Error::ForeignSourceId(c))
+        );
+        assert_eq!(
+            third.try_get_source_by_id(a, |_| ()),
+            Err(Error::ForeignSourceId(a))
+        );
+    }
+
+    #[cfg(feature = "u32-source-ids")]
+    #[test]
+    fn more_than_u16_sources() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let mut last = SourceId::NULL;
+        for idx in 0..(u16::MAX as usize + 10) {
+            last = fs.new_source_id(PathBuf::new(), idx.to_string());
+        }
+        assert_eq!(
+            fs.get_file_content(last),
+            (u16::MAX as usize + 9).to_string()
+        );
     }
 }
@@ -0,0 +1,583 @@
+//! Binary format for [MapFileStore]. Integers are little endian, lengths are `u64` and strings are
+//! a length followed by UTF-8
+
+use super::*;
+use crate::SourceOrigin;
+
+const MAGIC: &[u8; 4] = b"SMFS";
+/// Incremented on any change to the format
+const VERSION: u32 = 1;
+
+impl<M: PathMap> MapFileStore<M> {
+    /// Serialises every source (including its [LineStarts], revision, segments and [SourceMetadata]
+    /// other than `user_data`), removed slots, removals and the path map. Restoring with [MapFileStore::from_snapshot] gives the same [SourceId]s.
+    ///
+    /// Paths which are not valid UTF-8 are converted lossily
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.push(line_terminators_tag(self.line_terminators));
+        out.extend_from_slice(&self.next_generation.to_le_bytes());
+        out.extend_from_slice(&self.revision.to_le_bytes());
+        out.extend_from_slice(&self.tag.to_u16().to_le_bytes());
+
+        write_length(&mut out, self.sources.len());
+        for slot in &self.sources {
+            out.extend_from_slice(&slot.generation.to_le_bytes());
+            match &slot.source {
+                None => out.push(0),
+                Some(source) => {
+                    out.push(1);
+                    write_str(&mut out, &source.path.to_string_lossy());
+                    write_str(&mut out, &source.content);
+                    out.extend_from_slice(&source.revision.to_le_bytes());
+                    write_length(&mut out, source.line_starts.line_count());
+                    for start in source.line_starts.iter() {
+                        out.extend_from_slice(&(start as u32).to_le_bytes());
+                    }
+                    write_decoding(&mut out, source.decoding.as_ref());
+                    write_metadata(&mut out, &source.metadata);
+                    write_length(&mut out, source.segments.len());
+                    for segment in &source.segments {
+                        out.extend_from_slice(&segment.span.start.to_le_bytes());
+                        out.extend_from_slice(&segment.span.end.to_le_bytes());
+                        let original = segment.original;
+                        write_length(&mut out, original.source.position());
+                        out.extend_from_slice(&original.source.generation.to_le_bytes());
+                        out.extend_from_slice(&original.start.to_le_bytes());
+                        out.extend_from_slice(&original.end.to_le_bytes());
+                        out.extend_from_slice(&segment.original_revision.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        write_length(&mut out, self.removed.len());
+        for position in &self.removed {
+            write_length(&mut out, *position);
+        }
+
+        write_length(&mut out, self.removals.len());
+        for (revision, source_id) in &self.removals {
+            out.extend_from_slice(&revision.to_le_bytes());
+            write_length(&mut out, source_id.position());
+            out.extend_from_slice(&source_id.generation.to_le_bytes());
+        }
+
+        let mut entries = self.mappings.entries();
+        // So snapshots of the same store are the same
+        entries.sort_unstable_by_key(|(path, _)| *path);
+        write_length(&mut out, entries.len());
+        for (path, source_id) in entries {
+            write_str(&mut out, &path.to_string_lossy());
+            write_length(&mut out, source_id.position());
+            out.extend_from_slice(&source_id.generation.to_le_bytes());
+        }
+
+        out
+    }
+
+    /// Restores a store from [MapFileStore::to_snapshot]
+    pub fn from_snapshot(snapshot: &[u8]) -> Result<Self, Error>
+    where
+        M: Default,
+    {
+        Self::from_snapshot_with_path_map(snapshot, M::default())
+    }
+
+    /// [MapFileStore::from_snapshot] for configured path maps (which are not part of the snapshot).
+    /// For example [WithPathMap::case_insensitive]
+    pub fn from_snapshot_with_path_map(snapshot: &[u8], mut mappings: M) -> Result<Self, Error> {
+        let mut reader = Reader(snapshot);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        if reader.u32()? != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let line_terminators = match reader.u8()? {
+            0 => LineTerminators::LineFeed,
+            1 => LineTerminators::CarriageReturn,
+            2 => LineTerminators::JavaScript,
+            _ => return Err(invalid("unknown line terminators")),
+        };
+        let next_generation = reader.u16()?;
+        let revision = reader.u64()?;
+        let tag = StoreTag::from_u16(reader.u16()?);
+
+        let slot_count = reader.length()?;
+        if slot_count > SourceId::MAX_SOURCES {
+            return Err(invalid("too many sources"));
+        }
+        let mut sources = Vec::new();
+        for _ in 0..slot_count {
+            let generation = reader.u16()?;
+            let source = match reader.u8()? {
+                0 => None,
+                1 => {
+                    let path = reader.str()?;
+                    let content = reader.str()?;
+                    let source_revision = reader.u64()?;
+                    let line_count = reader.length()?;
+                    let mut starts = Vec::new();
+                    for _ in 0..line_count {
+                        starts.push(reader.u32()?);
+                    }
+                    check_line_starts(&starts, content)?;
+                    let line_starts = LineStarts::from_starts(starts, line_terminators);
+                    let decoding = read_decoding(&mut reader)?;
+                    let metadata = read_metadata(&mut reader)?;
+                    let segments = read_segments(&mut reader, content.len(), source_revision, tag)?;
+                    Some(Source {
+                        revision: source_revision,
+                        decoding,
+                        metadata,
+                        segments,
+                        ..Source::new(path.into(), Arc::new(content.to_owned()), line_starts)
+                    })
+                }
+                _ => return Err(invalid("unknown slot kind")),
+            };
+            sources.push(Slot { generation, source });
+        }
+
+        let mut removed = Vec::new();
+        let mut is_removed = vec![false; sources.len()];
+        for _ in 0..reader.length()? {
+            let position = reader.length()?;
+            if sources
+                .get(position)
+                .is_none_or(|slot| slot.source.is_some())
+            {
+                return Err(invalid("removed position is not a removed source"));
+            }
+            // Reusing the slot would overflow its generation (`remove_file` never adds these)
+            if sources[position].generation == u16::MAX {
+                return Err(invalid("removed position has the last generation"));
+            }
+            // Otherwise two sources would be added at the same position
+            if std::mem::replace(&mut is_removed[position], true) {
+                return Err(invalid("removed position is repeated"));
+            }
+            removed.push(position);
+        }
+
+        let mut removals = Vec::<(u64, SourceId)>::new();
+        for _ in 0..reader.length()? {
+            let removed_at = reader.u64()?;
+            let position = reader.length()?;
+            let generation = reader.u16()?;
+            // The slot may since have been reused or dropped by `compact`
+            if removed_at > revision
+                || position >= SourceId::MAX_SOURCES
+                || removals.last().is_some_and(|(last, _)| *last > removed_at)
+            {
+                return Err(invalid("invalid removal"));
+            }
+            // A removed id must not be given out again. Slots only increase their generation and
+            // sources added after `compact` dropped a slot start at `next_generation`
+            let is_reusable = match sources.get(position) {
+                Some(slot) => {
+                    generation > slot.generation
+                        || (generation == slot.generation && slot.source.is_some())
+                }
+                None => generation >= next_generation,
+            };
+            if is_reusable {
+                return Err(invalid("removed source id could be reused"));
+            }
+            removals.push((
+                removed_at,
+                SourceId::from_index(position + 1, generation, tag),
+            ));
+        }
+
+        for _ in 0..reader.length()? {
+            let path = reader.str()?;
+            let position = reader.length()?;
+            let generation = reader.u16()?;
+            let slot = sources
+                .get(position)
+                .filter(|slot| slot.source.is_some() && slot.generation == generation)
+                .ok_or(invalid("path does not reference a source"))?;
+            mappings.set_path(
+                path.into(),
+                SourceId::from_index(position + 1, slot.generation, tag),
+            );
+        }
+
+        if !reader.0.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        Ok(Self {
+            sources,
+            removed,
+            removals,
+            next_generation,
+            revision,
+            mappings,
+            line_terminators,
+            tag,
+        })
+    }
+}
+
+/// `source_revision` is the revision of the source the segments are in
+fn read_segments(
+    reader: &mut Reader,
+    content_length: usize,
+    source_revision: u64,
+    tag: StoreTag,
+) -> Result<Vec<Segment>, Error> {
+    let mut segments = Vec::<Segment>::new();
+    for _ in 0..reader.length()? {
+        let span = Span {
+            start: reader.u32()?,
+            end: reader.u32()?,
+            source: (),
+        };
+        let position = reader.length()?;
+        let generation = reader.u16()?;
+        let (start, end) = (reader.u32()?, reader.u32()?);
+        let original_revision = reader.u64()?;
+        let is_valid = span.start <= span.end
+            && span.end as usize <= content_length
+            && start <= end
+            && span.end - span.start == end - start
+            && position < SourceId::MAX_SOURCES
+            && segments
+                .last()
+                .is_none_or(|last| last.span.end <= span.start);
+        if !is_valid {
+            return Err(invalid("invalid segment"));
+        }
+        // Sources are always copied from sources added (or changed) before them. Segments are
+        // only followed while the original has the same revision, so this means they cannot form
+        // cycles
+        if original_revision >= source_revision {
+            return Err(invalid("segment copied from a later source"));
+        }
+        // The original source may since have been removed, in which case resolving spans gives an
+        // error (as it would have before the snapshot)
+        segments.push(Segment {
+            span,
+            original: SpanWithSource {
+                start,
+                end,
+                source: SourceId::from_index(position + 1, generation, tag),
+            },
+            original_revision,
+        });
+    }
+    Ok(segments)
+}
+
+fn invalid(reason: &'static str) -> Error {
+    Error::InvalidSnapshot { reason }
+}
+
+fn line_terminators_tag(line_terminators: LineTerminators) -> u8 {
+    match line_terminators {
+        LineTerminators::LineFeed => 0,
+        LineTerminators::CarriageReturn => 1,
+        LineTerminators::JavaScript => 2,
+    }
+}
+
+/// Later lookups slice `content` at the line starts, so they must be valid
+fn check_line_starts(starts: &[u32], content: &str) -> Result<(), Error> {
+    let valid = starts.first() == Some(&0)
+        && starts.windows(2).all(|pair| pair[0] < pair[1])
+        && starts
+            .iter()
+            .all(|start| content.is_char_boundary(*start as usize));
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid("invalid line starts"))
+    }
+}
+
+fn write_decoding(out: &mut Vec<u8>, decoding: Option<&Decoding>) {
+    let Some(decoding) = decoding else {
+        out.push(0);
+        return;
+    };
+    out.push(1);
+    out.push(match decoding.encoding {
+        SourceEncoding::Utf8 => 0,
+        SourceEncoding::Utf16Le => 1,
+        SourceEncoding::Utf16Be => 2,
+        SourceEncoding::Latin1 => 3,
+    });
+    out.push(decoding.bom_length);
+    write_length(out, decoding.runs.len());
+    for run in &decoding.runs {
+        out.extend_from_slice(&run.decoded.to_le_bytes());
+        out.extend_from_slice(&run.original.to_le_bytes());
+        out.push(run.decoded_width);
+        out.push(run.original_width);
+    }
+}
+
+fn read_decoding(reader: &mut Reader) -> Result<Option<Decoding>, Error> {
+    if reader.u8()? == 0 {
+        return Ok(None);
+    }
+    let encoding = match reader.u8()? {
+        0 => SourceEncoding::Utf8,
+        1 => SourceEncoding::Utf16Le,
+        2 => SourceEncoding::Utf16Be,
+        3 => SourceEncoding::Latin1,
+        _ => return Err(invalid("unknown encoding")),
+    };
+    let bom_length = reader.u8()?;
+    let mut runs = Vec::new();
+    for _ in 0..reader.length()? {
+        let run = crate::decoding::Run {
+            decoded: reader.u32()?,
+            original: reader.u32()?,
+            decoded_width: reader.u8()?,
+            original_width: reader.u8()?,
+        };
+        if run.decoded_width == 0 || run.original_width == 0 {
+            return Err(invalid("invalid decoding"));
+        }
+        runs.push(run);
+    }
+    Ok(Some(Decoding {
+        encoding,
+        bom_length,
+        runs,
+    }))
+}
+
+fn write_metadata(out: &mut Vec<u8>, metadata: &SourceMetadata) {
+    match &metadata.language_id {
+        Some(language_id) => {
+            out.push(1);
+            write_str(out, language_id);
+        }
+        None => out.push(0),
+    }
+    match metadata.version {
+        Some(version) => {
+            out.push(1);
+            out.extend_from_slice(&version.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    out.push(match metadata.origin {
+        SourceOrigin::OnDisk => 0,
+        SourceOrigin::Virtual => 1,
+        SourceOrigin::Generated => 2,
+    });
+}
+
+fn read_metadata(reader: &mut Reader) -> Result<SourceMetadata, Error> {
+    let language_id = match reader.u8()? {
+        0 => None,
+        _ => Some(reader.str()?.to_owned()),
+    };
+    let version = match reader.u8()? {
+        0 => None,
+        _ => Some(reader.u32()? as i32),
+    };
+    let origin = match reader.u8()? {
+        0 => SourceOrigin::OnDisk,
+        1 => SourceOrigin::Virtual,
+        2 => SourceOrigin::Generated,
+        _ => return Err(invalid("unknown origin")),
+    };
+    Ok(SourceMetadata {
+        language_id,
+        version,
+        origin,
+        user_data: None,
+    })
+}
+
+fn write_length(out: &mut Vec<u8>, length: usize) {
+    out.extend_from_slice(&(length as u64).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_length(out, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if length > self.0.len() {
+            return Err(invalid("unexpected end"));
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        self.array().map(u8::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn length(&mut self) -> Result<usize, Error> {
+        self.u64()?
+            .try_into()
+            .map_err(|_| invalid("length too large"))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let length = self.length()?;
+        std::str::from_utf8(self.take(length)?).map_err(|_| invalid("invalid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_source_ids() {
+        let mut fs =
+            MapFileStore::<WithPathMap>::new_with_line_terminators(LineTerminators::CarriageReturn);
+        let a = fs.new_source_id("a.ts".into(), "let a = 1;\r\n".into());
+        let b = fs.new_source_id("b.ts".into(), "let b = 1;".into());
+        let c = fs.new_source_id("c.ts".into(), "let c = 1;\rlet d;".into());
+        fs.remove_file(b).unwrap();
+        fs.append_to_file(a, "let e;");
+
+        let snapshot = fs.to_snapshot();
+        assert_eq!(fs.to_snapshot(), snapshot);
+        let mut restored = MapFileStore::<WithPathMap>::from_snapshot(&snapshot).unwrap();
+
+        assert_eq!(restored.source_ids().collect::<Vec<_>>(), [a, c]);
+        assert_eq!(restored.get_source_at_path(Path::new("c.ts")), Some(c));
+        assert_eq!(restored.get_source_at_path(Path::new("b.ts")), None);
+        assert_eq!(restored.get_file_content(a), "let a = 1;\r\nlet e;");
+        assert_eq!(
+            restored.try_get_source_by_id(b, |_| ()),
+            Err(Error::StaleSourceId(b))
+        );
+        for source_id in [a, c] {
+            let get = |fs: &MapFileStore<WithPathMap>| {
+                fs.get_source_by_id(source_id, |source| {
+                    let starts = source.line_starts.iter().collect::<Vec<_>>();
+                    (starts, source.content_hash(), source.revision())
+                })
+            };
+            assert_eq!(get(&restored), get(&fs));
+        }
+        assert_eq!(restored.revision(), fs.revision());
+        assert_eq!(restored.removed_since(0).collect::<Vec<_>>(), [b]);
+
+        // New sources get the same ids as they would have
+        assert_eq!(
+            restored.new_source_id("d.ts".into(), String::new()),
+            fs.new_source_id("d.ts".into(), String::new())
+        );
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        fs.new_source_id("a.ts".into(), "let a = 1;\nlet b;".into());
+        let snapshot = fs.to_snapshot();
+
+        let from_snapshot = |snapshot: &[u8]| MapFileStore::<NoPathMap>::from_snapshot(snapshot);
+        assert!(from_snapshot(&snapshot).is_ok());
+        assert_eq!(
+            from_snapshot(b"not a snapshot").err(),
+            Some(invalid("not a snapshot"))
+        );
+        assert_eq!(
+            from_snapshot(&snapshot[..snapshot.len() - 1]).err(),
+            Some(invalid("unexpected end"))
+        );
+
+        let mut future = snapshot.clone();
+        future[4] = VERSION as u8 + 1;
+        assert_eq!(
+            from_snapshot(&future).err(),
+            Some(invalid("unsupported version"))
+        );
+
+        let mut cyclic = MapFileStore::<NoPathMap>::default();
+        let a = cyclic.new_source_id("a.txt".into(), "a".into());
+        let whole = |source| SpanWithSource {
+            start: 0,
+            end: 1,
+            source,
+        };
+        let b = cyclic
+            .new_embedded_source_id("b.txt".into(), whole(a))
+            .unwrap();
+        let segment = Segment {
+            original_revision: cyclic.get_source_by_id(b, Source::revision),
+            ..Segment::new(whole(b).without_source(), whole(b))
+        };
+        cyclic.get_source_mut(a).unwrap().segments = vec![segment];
+        assert_eq!(
+            MapFileStore::<NoPathMap>::from_snapshot(&cyclic.to_snapshot()).err(),
+            Some(invalid("segment copied from a later source"))
+        );
+
+        let b = fs.new_source_id("b.ts".into(), String::new());
+        fs.remove_file(b).unwrap();
+        fs.removed.push(b.position());
+        assert_eq!(
+            from_snapshot(&fs.to_snapshot()).err(),
+            Some(invalid("removed position is repeated"))
+        );
+
+        let mut last_generation = MapFileStore::<NoPathMap>::default();
+        let c = last_generation.new_source_id("c.ts".into(), String::new());
+        last_generation.sources[c.position()].generation = u16::MAX;
+        last_generation.sources[c.position()].source = None;
+        last_generation.removed.push(c.position());
+        assert_eq!(
+            from_snapshot(&last_generation.to_snapshot()).err(),
+            Some(invalid("removed position has the last generation"))
+        );
+
+        // The dropped slot's id would be given to the next source
+        let mut compacted = MapFileStore::<NoPathMap>::default();
+        let d = compacted.new_source_id("d.ts".into(), String::new());
+        compacted.remove_file(d).unwrap();
+        compacted.compact();
+        assert!(from_snapshot(&compacted.to_snapshot()).is_ok());
+        compacted.next_generation = d.generation;
+        assert_eq!(
+            from_snapshot(&compacted.to_snapshot()).err(),
+            Some(invalid("removed source id could be reused"))
+        );
+
+        // The live source has the removed id
+        let mut live = MapFileStore::<NoPathMap>::default();
+        let e = live.new_source_id("e.ts".into(), String::new());
+        live.removals.push((live.revision, e));
+        assert_eq!(
+            from_snapshot(&live.to_snapshot()).err(),
+            Some(invalid("removed source id could be reused"))
+        );
+    }
+}
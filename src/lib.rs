@@ -1,20 +1,38 @@
 #![allow(clippy::useless_conversion)]
 #![doc = include_str!("../README.md")]
 
+mod concurrent_file_store;
+mod decoding;
+mod disk_file_store;
 pub mod encodings;
+mod error;
+mod escapes;
 mod filesystem;
 mod lines_columns_indexes;
+mod metadata;
+mod offset_map;
+mod overlay_file_store;
+pub mod paths;
+#[cfg(feature = "lsp-types-morphisms")]
+mod semantic_tokens;
 mod source_id;
 mod span;
 mod to_string;
 
-use std::{
-    collections::{HashMap, HashSet},
-    convert::TryInto,
-};
+use std::collections::{HashMap, HashSet};
 
+pub use concurrent_file_store::ConcurrentFileStore;
+pub use decoding::{decode, Decoding, SourceEncoding};
+pub use disk_file_store::DiskFileStore;
+pub use error::Error;
+pub use escapes::{decode_escapes, EscapeGrammar};
 pub use filesystem::*;
-pub use lines_columns_indexes::LineStarts;
+pub use lines_columns_indexes::{LineStartIndexes, LineStarts, LineTerminators};
+pub use metadata::{SourceMetadata, SourceOrigin};
+pub use offset_map::OffsetMap;
+pub use overlay_file_store::OverlayFileStore;
+#[cfg(feature = "lsp-types-morphisms")]
+pub use semantic_tokens::*;
 pub use source_id::SourceId;
 pub use span::*;
 pub use to_string::*;
@@ -70,6 +88,10 @@ pub struct SourceMapBuilder {
     // last_output_column: usize,
     mappings: Vec<MappingOrBreak>,
     used_sources: HashSet<SourceId>,
+    line_terminators: LineTerminators,
+    /// The output so far ends with `\r`, so a `\n` at the start of the next slice is part of the
+    /// same line terminator
+    after_carriage_return: bool,
 }
 
 impl SourceMapBuilder {
@@ -77,15 +99,49 @@ impl SourceMapBuilder {
         SourceMapBuilder::default()
     }
 
+    /// For when the output is read by something which ends lines at more than `\n`. For example
+    /// JavaScript output should use [LineTerminators::JavaScript]
+    pub fn new_with_line_terminators(line_terminators: LineTerminators) -> SourceMapBuilder {
+        SourceMapBuilder {
+            line_terminators,
+            ..SourceMapBuilder::default()
+        }
+    }
+
+    pub fn line_terminators(&self) -> LineTerminators {
+        self.line_terminators
+    }
+
     // Record a new line was added to output
     pub fn add_new_line(&mut self) {
         self.current_output_line += 1;
         self.mappings.push(MappingOrBreak::Break);
+        self.after_carriage_return = false;
+    }
+
+    /// Records a new line for each line terminator in `slice`. A `\r\n` split between this and
+    /// the previous slice is one line terminator
+    pub fn add_new_lines_in(&mut self, slice: &str) {
+        if slice.is_empty() {
+            return;
+        }
+        let rest = match slice.strip_prefix('\n') {
+            Some(rest) if self.after_carriage_return => rest,
+            _ => slice,
+        };
+        for _ in self.line_terminators.line_start_indexes(rest) {
+            self.add_new_line();
+        }
+        self.after_carriage_return =
+            self.line_terminators.handles_carriage_return() && slice.ends_with('\r');
     }
 
     // Record a new line was added to output
     pub fn add_to_column(&mut self, length: usize) {
         self.current_output_column += length as u32;
+        if length > 0 {
+            self.after_carriage_return = false;
+        }
     }
 
     /// Original line and original column are one indexed
@@ -101,29 +157,58 @@ impl SourceMapBuilder {
 
         self.mappings.push(MappingOrBreak::Mapping(SourceMapping {
             from_source: *from_source,
-            source_byte_start: (*source_byte_start).try_into().unwrap(),
+            source_byte_start: *source_byte_start,
             on_output_column: current_column,
             // source_byte_end: *source_byte_end,
             // on_output_line: self.current_output_line,
         }));
     }
 
+    /// Moves mappings in `transformed` to the source `offset_map` is for. For when output was
+    /// generated from a transformed version of a source (see [OffsetMap])
+    pub fn apply_offset_map(&mut self, transformed: SourceId, offset_map: &OffsetMap) {
+        let mut applied = false;
+        for mapping in self.mappings.iter_mut() {
+            if let MappingOrBreak::Mapping(mapping) = mapping {
+                if mapping.from_source == transformed {
+                    mapping.source_byte_start =
+                        offset_map.to_original_offset(mapping.source_byte_start);
+                    mapping.from_source = offset_map.source();
+                    applied = true;
+                }
+            }
+        }
+        if applied {
+            self.used_sources.remove(&transformed);
+            self.used_sources.insert(offset_map.source());
+        }
+    }
+
     /// Encodes the results into a string and builds the JSON representation thingy
     ///
     /// TODO not 100% certain that this code is a the correct implementation
     ///
     /// TODO are the accounts for SourceId::null valid here...?
     pub fn build(self, fs: &impl FileSystem) -> SourceMap {
-        // Splits are indexes of new lines in the source
-        let mut source_line_splits = HashMap::<SourceId, LineStarts>::new();
+        self.try_build(fs).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [SourceMapBuilder::build] but returns an error rather than panicking if a
+    /// mapping references a source not in `fs`
+    pub fn try_build(self, fs: &impl FileSystem) -> Result<SourceMap, Error> {
+        // Splits are indexes of new lines in the source. Also holds the index of the source in `sources`
+        let mut source_line_splits = HashMap::<SourceId, (usize, LineStarts)>::new();
         let mut sources = Vec::<SourceId>::new();
+        // Embedded and concatenated sources, which are not in `sources`. Mappings in them are
+        // moved to the sources they were copied from
+        let mut copied_sources = HashSet::<SourceId>::new();
 
         for source_id in self.used_sources.into_iter().filter(|id| !id.is_null()) {
-            source_line_splits.insert(
-                source_id,
-                fs.get_source_by_id(source_id, |source| source.line_starts.clone()),
-            );
-            sources.push(source_id);
+            if fs.try_get_source_by_id(source_id, |source| !source.segments().is_empty())? {
+                copied_sources.insert(source_id);
+            } else {
+                add_source(fs, &mut source_line_splits, &mut sources, source_id)?;
+            }
         }
 
         let mut mappings = String::new();
@@ -159,14 +244,24 @@ impl SourceMapBuilder {
                     vlq_encode_integer_to_buffer(&mut mappings, output_column);
                     last_mapped_output_column = on_output_column;
 
-                    // Find index
-                    // TODO faster
-                    let idx = sources.iter().position(|sid| *sid == from_source).unwrap();
+                    let (from_source, source_byte_start) = if copied_sources.contains(&from_source)
+                    {
+                        let original = fs.try_resolve_original_span(SpanWithSource {
+                            start: source_byte_start,
+                            end: source_byte_start,
+                            source: from_source,
+                        })?;
+                        add_source(fs, &mut source_line_splits, &mut sources, original.source)?;
+                        (original.source, original.start)
+                    } else {
+                        (from_source, source_byte_start)
+                    };
+
+                    let (idx, line_splits_for_this_file) =
+                        source_line_splits.get(&from_source).unwrap();
 
                     // Encode index of source
-                    vlq_encode_integer_to_buffer(&mut mappings, idx as isize);
-
-                    let line_splits_for_this_file = source_line_splits.get(&from_source).unwrap();
+                    vlq_encode_integer_to_buffer(&mut mappings, *idx as isize);
 
                     let (source_line, source_column) = line_splits_for_this_file
                         .get_line_and_column_pos_is_on(source_byte_start as usize);
@@ -194,20 +289,41 @@ impl SourceMapBuilder {
             }
         }
 
-        SourceMap { mappings, sources }
+        Ok(SourceMap { mappings, sources })
     }
 }
 
-fn count_characters_on_last_line(s: &str) -> u32 {
-    let mut count = 0u32;
-    for b in s.as_bytes().iter().rev() {
-        if *b == b'\n' {
-            return count;
+/// Adds `source_id` to `sources` (if it is not already)
+fn add_source(
+    fs: &impl FileSystem,
+    source_line_splits: &mut HashMap<SourceId, (usize, LineStarts)>,
+    sources: &mut Vec<SourceId>,
+    source_id: SourceId,
+) -> Result<(), Error> {
+    if let std::collections::hash_map::Entry::Vacant(entry) = source_line_splits.entry(source_id) {
+        let line_starts =
+            fs.try_get_source_by_id(source_id, |source| source.line_starts.clone())?;
+        entry.insert((sources.len(), line_starts));
+        sources.push(source_id);
+    }
+    Ok(())
+}
+
+fn count_characters_on_last_line(s: &str, line_terminators: LineTerminators) -> u32 {
+    if let LineTerminators::LineFeed = line_terminators {
+        let mut count = 0u32;
+        for b in s.as_bytes().iter().rev() {
+            if *b == b'\n' {
+                return count;
+            }
+            // I think the byte count should be fine
+            count += 1;
         }
-        // I think the byte count should be fine
-        count += 1;
+        count
+    } else {
+        let last_line_start = line_terminators.line_start_indexes(s).last().unwrap_or(0);
+        (s.len() - last_line_start) as u32
     }
-    count
 }
 
 #[derive(Clone)]
@@ -226,30 +342,27 @@ impl SourceMap {
         } = self;
 
         let (mut sources, mut sources_content) = (String::new(), String::new());
-        for (idx, (path, content)) in sources_used
-            .into_iter()
-            .map(|source_id| filesystem.get_file_path_and_content(source_id))
-            .enumerate()
-        {
+        for (idx, source_id) in sources_used.into_iter().enumerate() {
             if idx != 0 {
                 sources.push(',');
                 sources_content.push(',');
             }
-            write!(
-                sources,
-                "\"{}\"",
-                path.display().to_string().replace('\\', "/")
-            )
-            .unwrap();
-            write!(
-                sources_content,
-                "\"{}\"",
-                content
-                    .replace('\n', "\\n")
-                    .replace('\r', "\\r")
-                    .replace('"', "\\\"")
-            )
-            .unwrap();
+            // Written from the borrowed content rather than copying the file
+            filesystem.get_source_by_id(source_id, |source| {
+                write!(sources, "\"{}\"", paths::to_url(&source.path)).unwrap();
+                sources_content.push('"');
+                for chunk in source.content.split_inclusive(['\n', '\r', '"']) {
+                    let (text, escape) = match chunk.as_bytes().last() {
+                        Some(b'\n') => (&chunk[..chunk.len() - 1], "\\n"),
+                        Some(b'\r') => (&chunk[..chunk.len() - 1], "\\r"),
+                        Some(b'"') => (&chunk[..chunk.len() - 1], "\\\""),
+                        _ => (chunk, ""),
+                    };
+                    sources_content.push_str(text);
+                    sources_content.push_str(escape);
+                }
+                sources_content.push('"');
+            });
         }
 
         format!(
@@ -260,7 +373,7 @@ impl SourceMap {
 
 #[cfg(test)]
 mod source_map_tests {
-    use super::vlq_encode_integer_to_buffer;
+    use super::*;
 
     fn vlq_encode_integer(value: isize) -> String {
         let mut buf = String::new();
@@ -276,4 +389,61 @@ mod source_map_tests {
         assert_eq!(vlq_encode_integer(123), "2H");
         assert_eq!(vlq_encode_integer(123456789), "qxmvrH");
     }
+
+    #[test]
+    fn line_terminators() {
+        let mut fs =
+            MapFileStore::<NoPathMap>::new_with_line_terminators(LineTerminators::JavaScript);
+        let source = fs.new_source_id("".into(), "x\u{2028}y".into());
+
+        let mut builder = SourceMapBuilder::new_with_line_terminators(LineTerminators::JavaScript);
+        builder.add_new_lines_in("a\u{2028}b\rc\r\nd");
+        builder.add_mapping(
+            &SpanWithSource {
+                start: 4,
+                end: 5,
+                source,
+            },
+            0,
+        );
+
+        assert_eq!(builder.build(&fs).mappings, ";;;AACA");
+
+        // `\r\n` split between pushes is one line terminator
+        let mut builder = SourceMapBuilder::new_with_line_terminators(LineTerminators::JavaScript);
+        builder.add_new_lines_in("a\r");
+        builder.add_new_lines_in("\nb\r");
+        builder.add_to_column(1);
+        builder.add_new_lines_in("\n");
+        builder.add_mapping(
+            &SpanWithSource {
+                start: 4,
+                end: 5,
+                source,
+            },
+            0,
+        );
+        assert_eq!(builder.build(&fs).mappings, ";;;AACA");
+    }
+
+    #[test]
+    fn to_json() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id("src\\a.ts".into(), "let a = \"a\";\r\n".into());
+
+        let mut builder = SourceMapBuilder::new();
+        builder.add_mapping(
+            &SpanWithSource {
+                start: 0,
+                end: 3,
+                source,
+            },
+            0,
+        );
+
+        assert_eq!(
+            builder.build(&fs).to_json(&fs),
+            r#"{"version":3,"sourceRoot":"","sources":["src/a.ts"],"sourcesContent":["let a = \"a\";\r\n"],"names":[],"mappings":"AAAA"}"#
+        );
+    }
 }
@@ -1,32 +1,228 @@
-/// Contains the byte indexes of when line starts
+use std::{convert::TryInto, ops::Range, sync::Arc};
+
+/// Which character sequences end a line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LineTerminators {
+    /// Only `\n`. `\r\n` is still handled as the `\r` is part of the previous line
+    #[default]
+    LineFeed,
+    /// `\n`, `\r\n` and a lone `\r`
+    CarriageReturn,
+    /// `\n`, `\r\n`, a lone `\r`, U+2028 (line separator) and U+2029 (paragraph separator). The
+    /// [ECMAScript definition](https://tc39.es/ecma262/#sec-line-terminators) which source map
+    /// consumers use
+    JavaScript,
+}
+
+impl LineTerminators {
+    /// Returns the byte index after every line terminator in `source`
+    pub fn line_start_indexes(self, source: &str) -> LineStartIndexes<'_> {
+        LineStartIndexes {
+            bytes: source.as_bytes(),
+            position: 0,
+            terminators: self,
+        }
+    }
+
+    pub(crate) fn handles_carriage_return(self) -> bool {
+        !matches!(self, LineTerminators::LineFeed)
+    }
+}
+
+/// Iterator from [LineTerminators::line_start_indexes]
+pub struct LineStartIndexes<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    terminators: LineTerminators,
+}
+
+impl Iterator for LineStartIndexes<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let bytes = self.bytes;
+        let mut idx = self.position;
+        loop {
+            idx = self.skip_to_candidate(idx);
+            if idx >= bytes.len() {
+                break;
+            }
+
+            let after = match (bytes[idx], self.terminators) {
+                (b'\n', _) => Some(idx + 1),
+                (b'\r', LineTerminators::CarriageReturn | LineTerminators::JavaScript) => {
+                    if bytes.get(idx + 1) == Some(&b'\n') {
+                        Some(idx + 2)
+                    } else {
+                        Some(idx + 1)
+                    }
+                }
+                // U+2028 and U+2029 are encoded as E2 80 A8 and E2 80 A9
+                (0xE2, LineTerminators::JavaScript)
+                    if bytes.get(idx + 1) == Some(&0x80)
+                        && matches!(bytes.get(idx + 2), Some(0xA8 | 0xA9)) =>
+                {
+                    Some(idx + 3)
+                }
+                _ => None,
+            };
+            if let Some(after) = after {
+                self.position = after;
+                return Some(after);
+            }
+            idx += 1;
+        }
+        self.position = bytes.len();
+        None
+    }
+}
+
+impl LineStartIndexes<'_> {
+    /// Returns the index of the first byte from `idx` which could be (the start of) a terminator
+    fn skip_to_candidate(&self, idx: usize) -> usize {
+        let rest = &self.bytes[idx..];
+        let offset = match self.terminators {
+            LineTerminators::LineFeed => memchr::memchr(b'\n', rest),
+            LineTerminators::CarriageReturn => memchr::memchr2(b'\n', b'\r', rest),
+            // U+2028 and U+2029 start with 0xE2
+            LineTerminators::JavaScript => memchr::memchr3(b'\n', b'\r', 0xE2, rest),
+        };
+        offset.map_or(self.bytes.len(), |offset| idx + offset)
+    }
+}
+
+/// Contains the byte indexes of when line starts. Indexes are stored as `u32` (the same as
+/// [crate::Span]s) and shared, so cloning is cheap. Edits copy the indexes only if they are shared
 #[derive(Clone, Debug)]
-pub struct LineStarts(pub(crate) Vec<usize>);
+pub struct LineStarts {
+    starts: Arc<Vec<u32>>,
+    terminators: LineTerminators,
+}
 
 impl LineStarts {
     /// Implementation copied from [codespan-reporting](https://docs.rs/codespan-reporting/0.11.1/codespan_reporting/)
     pub fn new(source: &str) -> LineStarts {
-        Self(
-            std::iter::once(0)
-                .chain(source.match_indices('\n').map(|(i, _)| i + 1))
-                .collect(),
-        )
+        Self::new_with_line_terminators(source, LineTerminators::default())
+    }
+
+    pub fn new_with_line_terminators(source: &str, terminators: LineTerminators) -> LineStarts {
+        let starts = std::iter::once(0)
+            .chain(terminators.line_start_indexes(source))
+            .map(to_u32)
+            .collect();
+        Self {
+            starts: Arc::new(starts),
+            terminators,
+        }
     }
 
+    /// `starts` must be the (ascending) line starts of the source using `terminators`
+    pub(crate) fn from_starts(starts: Vec<u32>, terminators: LineTerminators) -> LineStarts {
+        Self {
+            starts: Arc::new(starts),
+            terminators,
+        }
+    }
+
+    pub fn line_terminators(&self) -> LineTerminators {
+        self.terminators
+    }
+
+    /// Number of lines. Always at least one
+    pub fn line_count(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Byte index of the start of `line` (0 indexed)
+    pub fn get_line_start(&self, line: usize) -> Option<usize> {
+        self.starts.get(line).map(|start| *start as usize)
+    }
+
+    /// Byte indexes of the start of every line
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.starts.iter().map(|start| *start as usize)
+    }
+
+    /// `start` is the length of the existing content. Use [LineStarts::append_after] if using
+    /// [LineTerminators] which handle `\r` and the existing content could end with a `\r`
     pub fn append(&mut self, start: usize, appended: &str) {
-        self.0
-            .extend(appended.match_indices('\n').map(|(i, _)| i + 1 + start))
+        let terminators = self.terminators;
+        Arc::make_mut(&mut self.starts).extend(
+            terminators
+                .line_start_indexes(appended)
+                .map(|index| to_u32(index + start)),
+        )
+    }
+
+    /// Same as [LineStarts::append] but handles a `\r\n` which is split between `existing` and `appended`
+    pub fn append_after(&mut self, existing: &str, appended: &str) {
+        if self.terminators.handles_carriage_return()
+            && existing.ends_with('\r')
+            && appended.starts_with('\n')
+        {
+            // The `\r` ended a line which now ends after the `\n`
+            if let Some(last) = Arc::make_mut(&mut self.starts).last_mut() {
+                *last += 1;
+            }
+            self.append(existing.len() + 1, &appended[1..]);
+        } else {
+            self.append(existing.len(), appended);
+        }
+    }
+
+    /// Updates line starts for `range` of the content being replaced with `new_text`. Only scans
+    /// `new_text` rather than the content, but if the length changes the starts of the lines after
+    /// `range` are shifted, which is proportional to the number of those lines. Use
+    /// [LineStarts::replace_in] if using [LineTerminators] which handle `\r`
+    pub fn replace(&mut self, range: Range<usize>, new_text: &str) {
+        debug_assert!(range.start <= range.end);
+        let terminators = self.terminators;
+        let starts = Arc::make_mut(&mut self.starts);
+        // Line starts after terminators inside `range`
+        let first_replaced = starts.partition_point(|index| *index as usize <= range.start);
+        let after_replaced = starts.partition_point(|index| *index as usize <= range.end);
+
+        if new_text.len() != range.len() {
+            for index in &mut starts[after_replaced..] {
+                *index = to_u32(*index as usize + new_text.len() - range.len());
+            }
+        }
+
+        let inserted = terminators
+            .line_start_indexes(new_text)
+            .map(|index| to_u32(index + range.start));
+        starts.splice(first_replaced..after_replaced, inserted);
+    }
+
+    /// Same as [LineStarts::replace] but handles `\r\n`s formed or broken at the edges of the edit.
+    /// `existing` is the content **before** the edit
+    pub fn replace_in(&mut self, existing: &str, mut range: Range<usize>, new_text: &str) {
+        if !self.terminators.handles_carriage_return() {
+            return self.replace(range, new_text);
+        }
+
+        let bytes = existing.as_bytes();
+        let mut prefix = "";
+        let mut suffix = "";
+        if range.start > 0 && bytes[range.start - 1] == b'\r' {
+            range.start -= 1;
+            prefix = "\r";
+        }
+        if bytes.get(range.end) == Some(&b'\n') {
+            range.end += 1;
+            suffix = "\n";
+        }
+
+        if prefix.is_empty() && suffix.is_empty() {
+            self.replace(range, new_text);
+        } else {
+            self.replace(range, &format!("{prefix}{new_text}{suffix}"));
+        }
     }
 
     pub fn byte_indexes_on_same_line(&self, pos1: usize, pos2: usize) -> bool {
         debug_assert!(pos1 <= pos2);
-        self.0
-            .windows(2)
-            .find_map(|w| {
-                let range = w[0]..=w[1];
-                range.contains(&pos1).then_some(range)
-            })
-            .expect("could not find splits for pos1")
-            .contains(&pos2)
+        self.get_line_pos_is_on(pos1) == self.get_line_pos_is_on(pos2)
     }
 
     pub fn byte_indexes_crosses_lines(&self, pos1: usize, pos2: usize) -> usize {
@@ -47,21 +243,52 @@ impl LineStarts {
 
     /// 0 indexed
     pub(crate) fn get_line_and_column_pos_is_on(&self, pos: usize) -> (usize, usize) {
-        let backwards_index = self
-            .0
-            .iter()
-            .rev()
-            .position(|index| pos >= *index)
+        let line = self
+            .starts
+            .partition_point(|index| *index as usize <= pos)
+            .checked_sub(1)
             .expect("pos out of bounds");
 
-        let line = (self.0.len() - 1) - backwards_index;
-        (line, pos - self.0[line])
+        (line, pos - self.starts[line] as usize)
+    }
+
+    /// Line and column (both 0 indexed, column in bytes) for each of `positions`. `positions` **must be
+    /// sorted** (ascending). Searches forward from the previous result, so is faster than
+    /// repeated single lookups when positions are close together
+    pub fn get_lines_and_columns_of_sorted_positions<'a>(
+        &'a self,
+        positions: impl IntoIterator<Item = usize> + 'a,
+    ) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let starts = &self.starts;
+        let mut line = 0;
+        let mut last = 0;
+        positions.into_iter().map(move |pos| {
+            debug_assert!(last <= pos, "positions not sorted");
+            last = pos;
+
+            // Exponential search forward from the current line
+            let mut step = 1;
+            while line + step < starts.len() && starts[line + step] as usize <= pos {
+                line += step;
+                step *= 2;
+            }
+            let upper = (line + step).min(starts.len());
+            line += starts[line..upper].partition_point(|index| *index as usize <= pos) - 1;
+
+            (line, pos - starts[line] as usize)
+        })
     }
 }
 
+fn to_u32(index: usize) -> u32 {
+    index
+        .try_into()
+        .expect("source too large for line starts (over 4GB)")
+}
+
 #[cfg(test)]
 mod tests {
-    use super::LineStarts;
+    use super::{LineStarts, LineTerminators};
 
     fn get_source() -> String {
         std::fs::read_to_string("README.md").expect("No README")
@@ -75,7 +302,7 @@ mod tests {
         let expected_lines = source.lines().collect::<Vec<_>>();
         let mut actual_lines = Vec::new();
 
-        let mut iterator = line_starts.0.into_iter();
+        let mut iterator = line_starts.iter();
         let mut last = iterator.next().unwrap();
         for part in iterator {
             let value = &source[last..part];
@@ -99,7 +326,114 @@ mod tests {
         let mut left = LineStarts::new(left);
         left.append(at, right);
 
-        assert_eq!(whole.0, left.0);
+        assert_eq!(whole.starts, left.starts);
+    }
+
+    #[test]
+    fn line_terminators() {
+        let source = "a\nb\r\nc\rd\u{2028}e\u{2029}f";
+        let starts = |terminators| {
+            LineStarts::new_with_line_terminators(source, terminators)
+                .iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(starts(LineTerminators::LineFeed), vec![0, 2, 5]);
+        assert_eq!(starts(LineTerminators::CarriageReturn), vec![0, 2, 5, 7]);
+        assert_eq!(
+            starts(LineTerminators::JavaScript),
+            vec![0, 2, 5, 7, 11, 15]
+        );
+
+        // `\r\n` split across an append
+        for at in 0..=source.len() {
+            if !source.is_char_boundary(at) {
+                continue;
+            }
+            let (left, right) = source.split_at(at);
+            let mut appended =
+                LineStarts::new_with_line_terminators(left, LineTerminators::JavaScript);
+            appended.append_after(left, right);
+            assert_eq!(
+                appended.iter().collect::<Vec<_>>(),
+                starts(LineTerminators::JavaScript)
+            );
+        }
+    }
+
+    #[test]
+    fn replace() {
+        let source = get_source();
+
+        for (range, new_text) in [
+            (100..200, "a\nb\nc"),
+            (0..0, "\n\n"),
+            (50..300, ""),
+            (source.len()..source.len(), "\nend"),
+            (120..121, "x"),
+        ] {
+            let mut edited = source.clone();
+            edited.replace_range(range.clone(), new_text);
+
+            let mut line_starts = LineStarts::new(&source);
+            line_starts.replace(range, new_text);
+            assert_eq!(line_starts.starts, LineStarts::new(&edited).starts);
+        }
+
+        let source = "a\rb\r\nc\n";
+        for (range, new_text) in [
+            (2..2, "\n"),
+            (3..4, ""),
+            (4..4, "\r"),
+            (5..5, "\r"),
+            (1..5, "\u{2028}"),
+            (2..6, ""),
+        ] {
+            let mut edited = source.to_owned();
+            edited.replace_range(range.clone(), new_text);
+
+            let mut line_starts =
+                LineStarts::new_with_line_terminators(source, LineTerminators::JavaScript);
+            line_starts.replace_in(source, range, new_text);
+            assert_eq!(
+                line_starts.starts,
+                LineStarts::new_with_line_terminators(&edited, LineTerminators::JavaScript).starts,
+                "{edited:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn line_terminators_at_every_alignment() {
+        let terminators = ["\n", "\r\n", "\r", "\u{2028}", "\u{2029}", "\u{2027}", "é"];
+        let mut source = String::new();
+        for (idx, terminator) in terminators.iter().cycle().take(200).enumerate() {
+            source.push_str(&"x".repeat(idx % 19));
+            source.push_str(terminator);
+        }
+
+        let mut expected = vec![0];
+        let mut chars = source.char_indices().peekable();
+        while let Some((idx, chr)) = chars.next() {
+            match chr {
+                '\r' if chars.peek().map(|(_, next)| *next) == Some('\n') => {}
+                '\n' | '\r' | '\u{2028}' | '\u{2029}' => expected.push(idx + chr.len_utf8()),
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            LineStarts::new_with_line_terminators(&source, LineTerminators::JavaScript)
+                .iter()
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            LineStarts::new(&source).iter().collect::<Vec<_>>(),
+            std::iter::once(0)
+                .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+                .collect::<Vec<_>>()
+        );
     }
 
     #[test]
@@ -118,6 +452,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn line_and_column_lookups() {
+        let source = get_source();
+        let line_starts = LineStarts::new(&source);
+
+        let naive = |pos: usize| {
+            let line = source[..pos].matches('\n').count();
+            let column = pos - source[..pos].rfind('\n').map_or(0, |idx| idx + 1);
+            (line, column)
+        };
+
+        let positions = (0..=source.len()).step_by(7).collect::<Vec<_>>();
+        for pos in positions.iter().copied() {
+            assert_eq!(line_starts.get_line_and_column_pos_is_on(pos), naive(pos));
+        }
+
+        let batched = line_starts
+            .get_lines_and_columns_of_sorted_positions(positions.iter().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batched,
+            positions.into_iter().map(naive).collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn byte_indexes_on_same_line() {
         let source = get_source();
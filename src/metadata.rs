@@ -0,0 +1,48 @@
+use std::{any::Any, fmt, sync::Arc};
+
+/// Where the content of a source comes from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceOrigin {
+    /// A file, which may have been changed in memory since (for example by an editor)
+    #[default]
+    OnDisk,
+    /// Only exists in memory. For example an unsaved editor buffer or a REPL input
+    Virtual,
+    /// Output of a tool (for example a code generator or macro expansion)
+    Generated,
+}
+
+/// Information about a source other than its path and content. Kept when the content changes
+#[derive(Clone, Default)]
+pub struct SourceMetadata {
+    /// For example `typescript`. Can be a
+    /// [LSP language identifier](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#textDocumentItem)
+    pub language_id: Option<String>,
+    /// LSP document version, which increases after each change
+    pub version: Option<i32>,
+    pub origin: SourceOrigin,
+    /// Anything else. Not kept in snapshots
+    pub user_data: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl SourceMetadata {
+    /// `user_data` if it is a `T`
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_deref()?.downcast_ref()
+    }
+
+    pub fn set_user_data<T: Any + Send + Sync>(&mut self, user_data: T) {
+        self.user_data = Some(Arc::new(user_data));
+    }
+}
+
+impl fmt::Debug for SourceMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SourceMetadata")
+            .field("language_id", &self.language_id)
+            .field("version", &self.version)
+            .field("origin", &self.origin)
+            .field("user_data", &self.user_data.as_ref().map(|_| ..))
+            .finish()
+    }
+}
@@ -0,0 +1,238 @@
+use std::convert::TryInto;
+
+use crate::{Segment, SourceId, Span, SpanWithSource};
+
+/// Records how text was transformed (for example CRLF to LF, expanding tabs or decoding escapes)
+/// while building the transformed string, so positions in it can be translated back to the
+/// original source.
+///
+/// Built by calling [OffsetMap::copy], [OffsetMap::insert], [OffsetMap::delete] and
+/// [OffsetMap::replace] in the order the transformed string is built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetMap {
+    source: SourceId,
+    /// Sorted. Text between edits is the same in both
+    edits: Vec<Edit>,
+    transformed_length: u32,
+    original_length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edit {
+    transformed: u32,
+    original: u32,
+    transformed_length: u32,
+    original_length: u32,
+}
+
+impl OffsetMap {
+    /// For a transformation of (the start of) `source`
+    pub fn new(source: SourceId) -> Self {
+        Self {
+            source,
+            edits: Vec::new(),
+            transformed_length: 0,
+            original_length: 0,
+        }
+    }
+
+    pub fn source(&self) -> SourceId {
+        self.source
+    }
+
+    /// Records `length` bytes copied unchanged
+    pub fn copy(&mut self, length: usize) {
+        let length = to_u32(length);
+        self.transformed_length += length;
+        self.original_length += length;
+    }
+
+    /// Records `length` bytes added which are not in the original
+    pub fn insert(&mut self, length: usize) {
+        self.replace(0, length);
+    }
+
+    /// Records `length` bytes of the original which were skipped
+    pub fn delete(&mut self, length: usize) {
+        self.replace(length, 0);
+    }
+
+    /// Records `original_length` bytes of the original becoming `transformed_length` bytes. For
+    /// example `\r\n` to `\n` or `\u0041` to `A`
+    pub fn replace(&mut self, original_length: usize, transformed_length: usize) {
+        let (original_length, transformed_length) =
+            (to_u32(original_length), to_u32(transformed_length));
+        if original_length == 0 && transformed_length == 0 {
+            return;
+        }
+        self.edits.push(Edit {
+            transformed: self.transformed_length,
+            original: self.original_length,
+            transformed_length,
+            original_length,
+        });
+        self.transformed_length += transformed_length;
+        self.original_length += original_length;
+    }
+
+    /// Offset in the original of an offset in the transformed text. Offsets in replaced or inserted
+    /// text are moved to the start of what it replaced
+    pub fn to_original_offset(&self, offset: u32) -> u32 {
+        let index = self
+            .edits
+            .partition_point(|edit| edit.transformed <= offset);
+        match index.checked_sub(1).map(|index| self.edits[index]) {
+            Some(edit) if offset < edit.transformed + edit.transformed_length => edit.original,
+            Some(edit) => edit.after(offset),
+            None => offset,
+        }
+    }
+
+    /// Same as [OffsetMap::to_original_offset], but for the end of a span. Offsets in replaced or
+    /// inserted text are moved to the end of what it replaced and deleted text at `offset` is not
+    /// included
+    fn to_original_end(&self, offset: u32) -> u32 {
+        let index = self.edits.partition_point(|edit| edit.transformed < offset);
+        match index.checked_sub(1).map(|index| self.edits[index]) {
+            Some(edit) if offset < edit.transformed + edit.transformed_length => {
+                edit.original + edit.original_length
+            }
+            Some(edit) => edit.after(offset),
+            None => offset,
+        }
+    }
+
+    /// The section of the original source `span` (in the transformed text) is from. A span covering
+    /// part of a replacement covers all of what it replaced
+    pub fn to_original_span(&self, span: Span) -> SpanWithSource {
+        let start = self.to_original_offset(span.start);
+        SpanWithSource {
+            start,
+            end: self.to_original_end(span.end).max(start),
+            source: self.source,
+        }
+    }
+
+    /// Copied sections, for registering the transformed text with
+    /// [crate::MapFileStore::new_concatenated_source_id]
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let (mut transformed, mut original) = (0, 0);
+        let ends = self
+            .edits
+            .iter()
+            .map(|edit| {
+                (
+                    edit.transformed,
+                    edit.transformed + edit.transformed_length,
+                    edit.original + edit.original_length,
+                )
+            })
+            .chain(std::iter::once((
+                self.transformed_length,
+                self.transformed_length,
+                self.original_length,
+            )));
+        for (copied_end, next_transformed, next_original) in ends {
+            if copied_end > transformed {
+                segments.push(Segment::new(
+                    Span {
+                        start: transformed,
+                        end: copied_end,
+                        source: (),
+                    },
+                    SpanWithSource {
+                        start: original,
+                        end: original + (copied_end - transformed),
+                        source: self.source,
+                    },
+                ));
+            }
+            transformed = next_transformed;
+            original = next_original;
+        }
+        segments
+    }
+}
+
+impl Edit {
+    /// For `offset` after this edit (and before the next)
+    fn after(self, offset: u32) -> u32 {
+        self.original + self.original_length + (offset - self.transformed - self.transformed_length)
+    }
+}
+
+fn to_u32(length: usize) -> u32 {
+    length
+        .try_into()
+        .expect("text too large for offset map (over 4GB)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileSystem, MapFileStore, NoPathMap, SourceMapBuilder};
+
+    /// CRLF to LF and tabs to four spaces
+    fn normalise(text: &str, map: &mut OffsetMap) -> String {
+        let mut out = String::new();
+        let mut chars = text.char_indices().peekable();
+        while let Some((_, chr)) = chars.next() {
+            match chr {
+                '\r' if matches!(chars.peek(), Some((_, '\n'))) => map.delete(1),
+                '\t' => {
+                    out.push_str("    ");
+                    map.replace(1, 4);
+                }
+                chr => {
+                    out.push(chr);
+                    map.copy(chr.len_utf8());
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn translating_spans() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let text = "a\r\n\tb;\r\n";
+        let source = fs.new_source_id("a.txt".into(), text.into());
+        let mut map = OffsetMap::new(source);
+        let transformed = normalise(text, &mut map);
+        assert_eq!(transformed, "a\n    b;\n");
+
+        let span = |start, end| Span {
+            start,
+            end,
+            source: (),
+        };
+        let original = |start, end| SpanWithSource { start, end, source };
+
+        // `a`
+        assert_eq!(map.to_original_span(span(0, 1)), original(0, 1));
+        // The new line
+        assert_eq!(map.to_original_span(span(1, 2)), original(2, 3));
+        // `b;`
+        assert_eq!(map.to_original_span(span(6, 8)), original(4, 6));
+        // Part of the expanded tab
+        assert_eq!(map.to_original_span(span(3, 4)), original(3, 4));
+        assert_eq!(map.to_original_offset(9), 8);
+
+        let transformed_source = fs.new_source_id("a.normalised.txt".into(), transformed.clone());
+        let mut builder = SourceMapBuilder::new();
+        builder.add_mapping(&span(6, 8).with_source(transformed_source), 0);
+        builder.apply_offset_map(transformed_source, &map);
+        let source_map = builder.build(&fs);
+        assert_eq!(source_map.sources, vec![source]);
+        assert_eq!(source_map.mappings, "AACC");
+
+        let concatenated = fs
+            .new_concatenated_source_id("a.normalised.txt".into(), transformed, map.segments())
+            .unwrap();
+        assert_eq!(
+            fs.resolve_original_span(span(6, 8).with_source(concatenated)),
+            original(4, 6)
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    source_id::StoreTag, Error, FileSystem, FileSystemWithPaths, LineStarts, LineTerminators,
+    MapFileStore, Source, SourceId, SourceMetadata, SourceOrigin, WithPathMap,
+};
+
+/// A [FileSystem] where in-memory content (for example unsaved editor buffers) shadows the content
+/// of a base [FileSystem]. [SourceId]s are those of the base, so are the same whether content
+/// comes from the base or the overlay. The exception is untitled buffers (see
+/// [OverlayFileStore::open_untitled]) which are only in the overlay
+pub struct OverlayFileStore<B> {
+    base: B,
+    overlay: MapFileStore<WithPathMap>,
+    /// [SourceId] (of the base or an untitled buffer) to [SourceId] in `overlay`
+    slots: HashMap<SourceId, SourceId>,
+    untitled: HashMap<PathBuf, SourceId>,
+    /// Untitled buffers are given indexes counting down from [SourceId::MAX_SOURCES], so they do
+    /// not clash with the base unless it is nearly full. Indexes are not reused
+    untitled_count: usize,
+}
+
+impl<B: FileSystemWithPaths> OverlayFileStore<B> {
+    pub fn new(base: B) -> Self {
+        Self::new_with_line_terminators(base, LineTerminators::default())
+    }
+
+    /// `line_terminators` should be the same as the base
+    pub fn new_with_line_terminators(base: B, line_terminators: LineTerminators) -> Self {
+        Self {
+            base,
+            overlay: MapFileStore::new_with_line_terminators(line_terminators),
+            slots: HashMap::new(),
+            untitled: HashMap::new(),
+            untitled_count: 0,
+        }
+    }
+
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    pub fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    /// Shadows the content of `path` with `content`. Returns the base [SourceId] for `path`. For
+    /// buffers which are not files use [OverlayFileStore::open_untitled]
+    pub fn open(&mut self, path: &Path, content: String) -> SourceId {
+        if let Some(source_id) = self.untitled.get(path) {
+            let overlay_id = self.slots[source_id];
+            self.overlay.update_file(overlay_id, content);
+            return *source_id;
+        }
+        let source_id = self.base.get_or_create_source_id(path);
+        self.set_overlay(source_id, path.to_path_buf(), content);
+        source_id
+    }
+
+    /// Adds a buffer which is not in the base (for example a new unsaved file). It has a
+    /// [Virtual](crate::SourceOrigin::Virtual) origin and is removed when reverted
+    pub fn open_untitled(&mut self, path: &Path, content: String) -> SourceId {
+        if let Some(source_id) = self.untitled.get(path) {
+            let overlay_id = self.slots[source_id];
+            self.overlay.update_file(overlay_id, content);
+            return *source_id;
+        }
+        let source_id = SourceId::from_index(
+            SourceId::MAX_SOURCES - self.untitled_count,
+            0,
+            StoreTag::UNTAGGED,
+        );
+        self.untitled_count += 1;
+        let overlay_id = self.overlay.new_source_id(path.to_path_buf(), content);
+        self.overlay
+            .update_metadata(overlay_id, |metadata| {
+                metadata.origin = SourceOrigin::Virtual
+            })
+            .unwrap();
+        self.slots.insert(source_id, overlay_id);
+        self.untitled.insert(path.to_path_buf(), source_id);
+        source_id
+    }
+
+    /// Replaces the whole content of a source. Overlays it if it was not already
+    pub fn update(&mut self, source_id: SourceId, content: String) -> Result<(), Error> {
+        match self.slots.get(&source_id) {
+            Some(overlay_id) => {
+                self.overlay.update_file(*overlay_id, content);
+            }
+            None => {
+                let path = self
+                    .base
+                    .try_get_source_by_id(source_id, |source| source.path.clone())?;
+                self.set_overlay(source_id, path, content);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces `range` of the content of a source with `new_text`. If it was not already overlaid,
+    /// the overlay starts as the base content. Nothing is changed if this returns an error
+    pub fn edit(
+        &mut self,
+        source_id: SourceId,
+        range: Range<usize>,
+        new_text: &str,
+    ) -> Result<(), Error> {
+        if let Some(overlay_id) = self.slots.get(&source_id) {
+            return self.overlay.replace_in_file(*overlay_id, range, new_text);
+        }
+        let (path, content) = self.base.try_get_source_by_id(source_id, |source| {
+            (source.path.clone(), source.content.to_string())
+        })?;
+        self.set_overlay(source_id, path, content);
+        let result = self
+            .overlay
+            .replace_in_file(self.slots[&source_id], range, new_text);
+        if result.is_err() {
+            self.revert(source_id);
+        }
+        result
+    }
+
+    /// Removes the overlay (freeing its content) so content comes from the base again (for example
+    /// when the editor buffer is closed). Untitled buffers are removed entirely. Returns whether
+    /// the source was overlaid
+    pub fn revert(&mut self, source_id: SourceId) -> bool {
+        let Some(overlay_id) = self.slots.remove(&source_id) else {
+            return false;
+        };
+        let source = self.overlay.remove_file(overlay_id).unwrap();
+        if self.untitled.get(&source.path) == Some(&source_id) {
+            self.untitled.remove(&source.path);
+        }
+        self.overlay.compact();
+        true
+    }
+
+    pub fn is_overlaid(&self, source_id: SourceId) -> bool {
+        self.slots.contains_key(&source_id)
+    }
+
+    /// The overlay starts with the [SourceMetadata] of the base (if it has the source)
+    fn set_overlay(&mut self, source_id: SourceId, path: PathBuf, content: String) {
+        if let Some(overlay_id) = self.slots.get(&source_id) {
+            self.overlay.update_file(*overlay_id, content);
+            return;
+        }
+        let metadata = self
+            .base
+            .try_get_file_metadata(source_id)
+            .unwrap_or_default();
+        let overlay_id = self.overlay.new_source_id(path, content);
+        self.overlay
+            .update_metadata(overlay_id, |overlay_metadata| *overlay_metadata = metadata)
+            .unwrap();
+        self.slots.insert(source_id, overlay_id);
+    }
+}
+
+impl<B: FileSystemWithPaths> FileSystem for OverlayFileStore<B> {
+    /// Same as [OverlayFileStore::open]
+    fn new_source_id_with_line_starts(
+        &mut self,
+        path: PathBuf,
+        content: String,
+    ) -> (SourceId, LineStarts) {
+        let source_id = self.open(&path, content);
+        let line_starts = self.get_source_by_id(source_id, |source| source.line_starts.clone());
+        (source_id, line_starts)
+    }
+
+    fn try_get_source_by_id<T, F: for<'a> FnOnce(&'a Source) -> T>(
+        &self,
+        source_id: SourceId,
+        f: F,
+    ) -> Result<T, Error> {
+        match self.slots.get(&source_id) {
+            Some(overlay_id) => self.overlay.try_get_source_by_id(*overlay_id, f),
+            None => self.base.try_get_source_by_id(source_id, f),
+        }
+    }
+
+    fn try_get_file_metadata(&self, source_id: SourceId) -> Result<SourceMetadata, Error> {
+        match self.slots.get(&source_id) {
+            Some(overlay_id) => self.overlay.try_get_file_metadata(*overlay_id),
+            None => self.base.try_get_file_metadata(source_id),
+        }
+    }
+
+    /// Changes the overlay if the source is overlaid, otherwise the base
+    fn update_metadata(
+        &mut self,
+        source_id: SourceId,
+        f: impl FnOnce(&mut SourceMetadata),
+    ) -> Result<(), Error> {
+        match self.slots.get(&source_id) {
+            Some(overlay_id) => self.overlay.update_metadata(*overlay_id, f),
+            None => self.base.update_metadata(source_id, f),
+        }
+    }
+}
+
+impl<B: FileSystemWithPaths> FileSystemWithPaths for OverlayFileStore<B> {
+    fn get_or_create_source_id(&mut self, path: &Path) -> SourceId {
+        match self.untitled.get(path) {
+            Some(source_id) => *source_id,
+            None => self.base.get_or_create_source_id(path),
+        }
+    }
+
+    fn get_source_at_path(&self, path: &Path) -> Option<SourceId> {
+        match self.untitled.get(path) {
+            Some(source_id) => Some(*source_id),
+            None => self.base.get_source_at_path(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_shadows_base() {
+        let mut base = MapFileStore::<WithPathMap>::default();
+        let on_disk = base.new_source_id("a.ts".into(), "const a = 1;".into());
+        let mut fs = OverlayFileStore::new(base);
+
+        assert_eq!(fs.open(Path::new("a.ts"), "const a = 2;".into()), on_disk);
+        assert_eq!(fs.get_file_content(on_disk), "const a = 2;");
+
+        fs.edit(on_disk, 10..11, "3\nconst b = 4").unwrap();
+        assert_eq!(fs.get_file_content(on_disk), "const a = 3\nconst b = 4;");
+        assert_eq!(
+            fs.get_source_by_id(on_disk, |source| source.line_starts.line_count()),
+            2
+        );
+
+        assert!(fs.revert(on_disk));
+        assert!(!fs.is_overlaid(on_disk));
+        assert_eq!(fs.get_file_content(on_disk), "const a = 1;");
+
+        // Editing a source which is not overlaid starts from the base content
+        fs.edit(on_disk, 0..0, "export ").unwrap();
+        assert_eq!(fs.get_file_content(on_disk), "export const a = 1;");
+        assert_eq!(fs.base().get_file_content(on_disk), "const a = 1;");
+
+        // Buffers for sources which are not in the base
+        let untitled = fs.open_untitled(Path::new("untitled.ts"), "let x;".into());
+        assert_eq!(
+            fs.get_source_at_path(Path::new("untitled.ts")),
+            Some(untitled)
+        );
+        assert_eq!(fs.get_file_content(untitled), "let x;");
+        assert_eq!(fs.get_file_metadata(untitled).origin, SourceOrigin::Virtual);
+        assert_eq!(fs.base().get_source_at_path(Path::new("untitled.ts")), None);
+
+        // Reverting frees the overlay content
+        assert!(fs.revert(untitled));
+        assert!(fs.revert(on_disk));
+        assert_eq!(fs.overlay.source_ids().count(), 0);
+        assert_eq!(fs.get_source_at_path(Path::new("untitled.ts")), None);
+        assert!(fs.try_get_source_by_id(untitled, |_| ()).is_err());
+    }
+
+    #[test]
+    fn failed_edit() {
+        let mut base = MapFileStore::<WithPathMap>::default();
+        let a = base.new_source_id("a.ts".into(), "let a = 'é';".into());
+        let mut fs = OverlayFileStore::new(base);
+
+        assert_eq!(
+            fs.edit(a, 10..11, "e"),
+            Err(Error::NotOnCharBoundary { position: 10 })
+        );
+        assert!(!fs.is_overlaid(a));
+
+        fs.open(Path::new("a.ts"), "let b;".into());
+        assert_eq!(
+            fs.edit(a, 2..20, ""),
+            Err(Error::PositionOutOfBounds {
+                position: 20,
+                length: 6
+            })
+        );
+        assert_eq!(fs.get_file_content(a), "let b;");
+    }
+
+    #[test]
+    fn overlay_metadata() {
+        let mut base = MapFileStore::<WithPathMap>::default();
+        let a = base.new_source_id("a.ts".into(), "let a;".into());
+        let mut fs = OverlayFileStore::new(base);
+
+        // Changes the base when not overlaid
+        fs.update_metadata(a, |metadata| {
+            metadata.language_id = Some("typescript".into())
+        })
+        .unwrap();
+        fs.open(Path::new("a.ts"), "let b;".into());
+        fs.update_metadata(a, |metadata| metadata.version = Some(2))
+            .unwrap();
+
+        let metadata = fs.get_file_metadata(a);
+        assert_eq!(metadata.language_id.as_deref(), Some("typescript"));
+        assert_eq!(metadata.version, Some(2));
+        assert_eq!(fs.base().get_file_metadata(a).version, None);
+    }
+}
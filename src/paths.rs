@@ -0,0 +1,188 @@
+//! Normalising source paths (so the same file always has the same [crate::SourceId]) and converting
+//! them to and from URLs
+
+use std::path::{Path, PathBuf};
+
+/// Lexically normalises a path. `\` is treated as a separator (and becomes `/`), `.` components are
+/// removed and `..` components remove the previous component. Does not access the file system, so
+/// symbolic links are not resolved. Paths which are not valid UTF-8 are returned as is
+pub fn normalise(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) => PathBuf::from(normalise_str(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// [normalise] then lowercased, for case insensitive file systems
+pub fn normalise_case_insensitive(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) => PathBuf::from(normalise_str(path).to_lowercase()),
+        None => path.to_path_buf(),
+    }
+}
+
+fn normalise_str(path: &str) -> String {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    let (root, rest) = match path.strip_prefix(is_separator) {
+        Some(rest) => ("/", rest),
+        None if has_drive_letter(path) => match path[2..].strip_prefix(is_separator) {
+            Some(rest) => (&path[..3], rest),
+            None => (&path[..2], &path[2..]),
+        },
+        None => ("", path),
+    };
+
+    let mut components = Vec::new();
+    for component in rest.split(is_separator) {
+        match component {
+            "" | "." => {}
+            ".." => match components.last() {
+                Some(&last) if last != ".." => {
+                    components.pop();
+                }
+                // Cannot go above the root
+                _ if !root.is_empty() => {}
+                _ => components.push(".."),
+            },
+            component => components.push(component),
+        }
+    }
+
+    let mut normalised = root.replace('\\', "/");
+    normalised.push_str(&components.join("/"));
+    if normalised.is_empty() {
+        normalised.push('.');
+    }
+    normalised
+}
+
+/// Starts with a Windows drive letter such as `C:`
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// `file://` URI of an absolute path (Unix or Windows)
+pub fn to_file_uri(path: &Path) -> String {
+    let path = normalise(path);
+    let path = path.to_string_lossy();
+    let mut uri = String::from("file://");
+    if has_drive_letter(&path) {
+        uri.push('/');
+    }
+    percent_encode_to(&mut uri, &path);
+    uri
+}
+
+/// A URL for a path, as used for `sources` in source maps. Absolute paths are `file://` URIs and
+/// relative paths are relative URLs
+pub fn to_url(path: &Path) -> String {
+    let normalised = normalise(path);
+    let normalised = normalised.to_string_lossy();
+    if normalised.starts_with('/') || has_drive_letter(&normalised) {
+        to_file_uri(path)
+    } else {
+        let mut url = String::new();
+        percent_encode_to(&mut url, &normalised);
+        url
+    }
+}
+
+/// The path of a `file://` URI. Returns [None] for other URIs
+pub fn from_file_uri(uri: &str) -> Option<PathBuf> {
+    let rest = uri
+        .strip_prefix("file://")
+        .or_else(|| uri.strip_prefix("FILE://"))?;
+    // Only local files
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return None;
+    }
+    let decoded = percent_decode(path)?;
+    // `/C:/...` is `C:/...`
+    let decoded = match decoded.strip_prefix('/') {
+        Some(windows) if has_drive_letter(windows) => windows.to_owned(),
+        _ => decoded,
+    };
+    Some(PathBuf::from(decoded))
+}
+
+/// Characters allowed in a URL path segment (and `/`). Everything else is encoded
+fn is_url_path_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&byte)
+}
+
+fn percent_encode_to(out: &mut String, value: &str) {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for byte in value.bytes() {
+        if is_url_path_char(byte) {
+            out.push(byte as char);
+        } else {
+            out.push('%');
+            out.push(HEX[(byte >> 4) as usize] as char);
+            out.push(HEX[(byte & 0xF) as usize] as char);
+        }
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let high = (iter.next()? as char).to_digit(16)?;
+            let low = (iter.next()? as char).to_digit(16)?;
+            bytes.push((high * 16 + low) as u8);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalising() {
+        let normalise = |path: &str| normalise(Path::new(path)).to_str().unwrap().to_owned();
+
+        assert_eq!(normalise("./a.ts"), "a.ts");
+        assert_eq!(normalise("src/../a.ts"), "a.ts");
+        assert_eq!(normalise("src\\utils/./b.ts"), "src/utils/b.ts");
+        assert_eq!(normalise("../../a.ts"), "../../a.ts");
+        assert_eq!(normalise("src/.."), ".");
+        assert_eq!(normalise("/../a//b.ts"), "/a/b.ts");
+        assert_eq!(normalise("C:\\project\\..\\a.ts"), "C:/a.ts");
+        assert_eq!(
+            normalise_case_insensitive(Path::new("Src/A.ts")),
+            Path::new("src/a.ts")
+        );
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            to_file_uri(Path::new("/home/user/my project/a.ts")),
+            "file:///home/user/my%20project/a.ts"
+        );
+        assert_eq!(
+            to_file_uri(Path::new("C:\\Users\\a#1.ts")),
+            "file:///C:/Users/a%231.ts"
+        );
+        assert_eq!(to_url(Path::new("src\\ä.ts")), "src/%C3%A4.ts");
+        assert_eq!(to_url(Path::new("/a.ts")), "file:///a.ts");
+
+        assert_eq!(
+            from_file_uri("file:///home/user/my%20project/a.ts"),
+            Some(PathBuf::from("/home/user/my project/a.ts"))
+        );
+        assert_eq!(
+            from_file_uri("file:///c%3A/Users/a.ts"),
+            Some(PathBuf::from("c:/Users/a.ts"))
+        );
+        assert_eq!(from_file_uri("https://example.com/a.ts"), None);
+        assert_eq!(from_file_uri("file:///a%2"), None);
+    }
+}
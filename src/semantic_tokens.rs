@@ -0,0 +1,289 @@
+use crate::{encodings::StringEncoding, Error, FileSystem, Source, SpanWithSource};
+
+/// A highlighted section of a source. `token_type` and `token_modifiers_bitset` are indexes into
+/// the legend the server registered with the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemanticTokenSpan {
+    pub span: SpanWithSource,
+    pub token_type: u32,
+    pub token_modifiers_bitset: u32,
+}
+
+impl From<(SpanWithSource, u32, u32)> for SemanticTokenSpan {
+    fn from((span, token_type, token_modifiers_bitset): (SpanWithSource, u32, u32)) -> Self {
+        Self {
+            span,
+            token_type,
+            token_modifiers_bitset,
+        }
+    }
+}
+
+/// Builds the delta encoded `data` array of [lsp_types::SemanticTokens].
+///
+/// - `tokens` are sorted by start position. They must not overlap and must all be from the same source
+/// - `T` should be the position encoding negotiated with the client (normally [crate::encodings::Utf16])
+/// - If the client does not declare `multilineTokenSupport` then spans crossing lines are split into a token per line
+///
+/// Returns an error if a span is not in the source, starts after it ends, is not on character
+/// boundaries or overlaps another span
+pub fn encode_semantic_tokens<T: StringEncoding>(
+    tokens: impl IntoIterator<Item = impl Into<SemanticTokenSpan>>,
+    fs: &impl FileSystem,
+    multiline_token_support: bool,
+) -> Result<Vec<lsp_types::SemanticToken>, Error> {
+    let mut tokens = tokens.into_iter().map(Into::into).collect::<Vec<_>>();
+    let Some(source_id) = tokens.first().map(|token| token.span.source) else {
+        return Ok(Vec::new());
+    };
+    tokens.sort_by_key(|token| token.span.start);
+
+    fs.try_get_source_by_id(source_id, |source| {
+        let mut encoder = Encoder {
+            data: Vec::new(),
+            last_line: 0,
+            last_column: 0,
+        };
+        let mut previous_end = 0;
+
+        for SemanticTokenSpan {
+            span,
+            token_type,
+            token_modifiers_bitset,
+        } in tokens
+        {
+            debug_assert_eq!(
+                span.source, source_id,
+                "semantic tokens from different sources"
+            );
+
+            let (start, end) = (span.start as usize, span.end as usize);
+            source.check_position(start)?;
+            source.check_position(end)?;
+            if start > end {
+                return Err(Error::InvalidRange { start, end });
+            }
+            if start < previous_end {
+                return Err(Error::OverlappingSpans {
+                    start,
+                    previous_end,
+                });
+            }
+            previous_end = end;
+
+            let start_line = source.line_starts.get_line_pos_is_on(start);
+            let end_line = source.line_starts.get_line_pos_is_on(end);
+
+            if start_line == end_line || multiline_token_support {
+                let column = column_of::<T>(source, start_line, start);
+                let length = T::get_encoded_length(&source.content[start..end]);
+                encoder.push(
+                    start_line,
+                    column,
+                    length,
+                    token_type,
+                    token_modifiers_bitset,
+                );
+            } else {
+                for line in start_line..=end_line {
+                    let line_start = source.line_starts.get_line_start(line).unwrap();
+                    let from = start.max(line_start);
+                    let to = end.min(line_content_end(source, line));
+                    if from >= to {
+                        continue;
+                    }
+                    let column = column_of::<T>(source, line, from);
+                    let length = T::get_encoded_length(&source.content[from..to]);
+                    encoder.push(line, column, length, token_type, token_modifiers_bitset);
+                }
+            }
+        }
+
+        Ok(encoder.data)
+    })?
+}
+
+struct Encoder {
+    data: Vec<lsp_types::SemanticToken>,
+    last_line: usize,
+    last_column: usize,
+}
+
+impl Encoder {
+    fn push(
+        &mut self,
+        line: usize,
+        column: usize,
+        length: usize,
+        token_type: u32,
+        token_modifiers_bitset: u32,
+    ) {
+        if length == 0 {
+            return;
+        }
+        let delta_line = line - self.last_line;
+        let delta_start = if delta_line == 0 {
+            column - self.last_column
+        } else {
+            column
+        };
+        self.data.push(lsp_types::SemanticToken {
+            delta_line: delta_line as u32,
+            delta_start: delta_start as u32,
+            length: length as u32,
+            token_type,
+            token_modifiers_bitset,
+        });
+        self.last_line = line;
+        self.last_column = column;
+    }
+}
+
+fn column_of<T: StringEncoding>(source: &Source, line: usize, pos: usize) -> usize {
+    let line_start = source.line_starts.get_line_start(line).unwrap();
+    T::get_encoded_length(&source.content[line_start..pos])
+}
+
+/// Byte index of the end of the line, excluding the line terminator
+fn line_content_end(source: &Source, line: usize) -> usize {
+    let end = source
+        .line_starts
+        .get_line_start(line + 1)
+        .unwrap_or(source.content.len());
+    let line_content = &source.content[..end];
+    let line_content = line_content
+        .strip_suffix(['\u{2028}', '\u{2029}'])
+        .unwrap_or(line_content);
+    let line_content = line_content.strip_suffix('\n').unwrap_or(line_content);
+    let line_content = line_content.strip_suffix('\r').unwrap_or(line_content);
+    line_content.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encodings::{Utf16, Utf8},
+        MapFileStore, NoPathMap, SourceId,
+    };
+
+    const SOURCE: &str = "let a = 2;\nconst b = \"😀\" + a;\n/* multi\nline */";
+
+    fn span(source: SourceId, find: &str) -> SpanWithSource {
+        let start = SOURCE.find(find).unwrap() as u32;
+        SpanWithSource {
+            start,
+            end: start + find.len() as u32,
+            source,
+        }
+    }
+
+    fn data(tokens: &[lsp_types::SemanticToken]) -> Vec<[u32; 5]> {
+        tokens
+            .iter()
+            .map(|t| {
+                [
+                    t.delta_line,
+                    t.delta_start,
+                    t.length,
+                    t.token_type,
+                    t.token_modifiers_bitset,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_encoding() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id("".into(), SOURCE.into());
+
+        let tokens = [
+            (span(source, "let"), 0, 0),
+            (span(source, "a ="), 1, 1),
+            (span(source, "const"), 0, 0),
+            (span(source, "\"😀\""), 2, 0),
+            (span(source, "+ a"), 1, 2),
+        ];
+
+        assert_eq!(
+            data(&encode_semantic_tokens::<Utf16>(tokens, &fs, false).unwrap()),
+            vec![
+                [0, 0, 3, 0, 0],
+                [0, 4, 3, 1, 1],
+                [1, 0, 5, 0, 0],
+                [0, 10, 4, 2, 0],
+                [0, 5, 3, 1, 2],
+            ]
+        );
+
+        // The emoji is one character but two UTF-16 code units
+        assert_eq!(
+            data(&encode_semantic_tokens::<Utf8>(tokens, &fs, false).unwrap())[3..],
+            [[0, 10, 3, 2, 0], [0, 4, 3, 1, 2]]
+        );
+    }
+
+    #[test]
+    fn multiline_tokens() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id("".into(), SOURCE.into());
+
+        let tokens = [
+            (span(source, "a;"), 1, 0),
+            (span(source, "/* multi\nline */"), 3, 0),
+        ];
+
+        assert_eq!(
+            data(&encode_semantic_tokens::<Utf16>(tokens, &fs, true).unwrap()),
+            vec![[1, 17, 2, 1, 0], [1, 0, 16, 3, 0]]
+        );
+        assert_eq!(
+            data(&encode_semantic_tokens::<Utf16>(tokens, &fs, false).unwrap()),
+            vec![[1, 17, 2, 1, 0], [1, 0, 8, 3, 0], [1, 0, 7, 3, 0]]
+        );
+    }
+
+    #[test]
+    fn unsorted_and_invalid_tokens() {
+        let mut fs = MapFileStore::<NoPathMap>::default();
+        let source = fs.new_source_id("".into(), SOURCE.into());
+        let encode = |tokens: &[(SpanWithSource, u32, u32)]| {
+            encode_semantic_tokens::<Utf16>(tokens.iter().copied(), &fs, false)
+        };
+
+        // Sorted before encoding
+        assert_eq!(
+            encode(&[(span(source, "const"), 0, 0), (span(source, "let"), 0, 0)]).map(|d| data(&d)),
+            Ok(vec![[0, 0, 3, 0, 0], [1, 0, 5, 0, 0]])
+        );
+
+        let at = |start, end| SpanWithSource { start, end, source };
+        assert_eq!(
+            encode(&[(at(0, 3), 0, 0), (at(2, 5), 0, 0)]),
+            Err(Error::OverlappingSpans {
+                start: 2,
+                previous_end: 3
+            })
+        );
+        assert_eq!(
+            encode(&[(at(0, 100), 0, 0)]),
+            Err(Error::PositionOutOfBounds {
+                position: 100,
+                length: SOURCE.len()
+            })
+        );
+        assert_eq!(
+            encode(&[(at(5, 2), 0, 0)]),
+            Err(Error::InvalidRange { start: 5, end: 2 })
+        );
+        // Inside the emoji
+        let emoji = SOURCE.find('😀').unwrap() as u32;
+        assert_eq!(
+            encode(&[(at(emoji + 1, emoji + 4), 0, 0)]),
+            Err(Error::NotOnCharBoundary {
+                position: emoji as usize + 1
+            })
+        );
+    }
+}
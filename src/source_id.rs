@@ -1,23 +1,155 @@
 use crate::FileSystem;
-use std::{fmt, path::PathBuf};
+use std::{convert::TryInto, fmt, path::PathBuf};
 
-/// A identifier for a [crate::Source]
+/// A identifier for a [crate::Source]. The `generation` distinguishes sources which reuse the index of
+/// a removed source, so stale ids are detected.
+///
+/// By default a store can hold up to 65,535 sources. The `u32-source-ids` feature raises this to
+/// 4,294,967,295 (at the cost of [crate::SpanWithSource] being 16 rather than 12 bytes).
+///
+/// With the `source-id-store-tags` feature, ids also record the store which created them. Using an
+/// id with another store is then [crate::Error::ForeignSourceId] rather than a different source
+///
+/// Serializes as just the index (a number), the same as before generations were added
 #[derive(PartialEq, Eq, Clone, Copy, Hash)]
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize))]
 #[cfg_attr(target_family = "wasm", derive(tsify::Tsify))]
-pub struct SourceId(pub(crate) u16);
+#[cfg_attr(
+    any(feature = "serde-serialize", target_family = "wasm"),
+    serde(transparent)
+)]
+pub struct SourceId {
+    #[cfg(not(feature = "u32-source-ids"))]
+    pub(crate) index: u16,
+    #[cfg(feature = "u32-source-ids")]
+    pub(crate) index: u32,
+    #[cfg_attr(any(feature = "serde-serialize", target_family = "wasm"), serde(skip))]
+    pub(crate) generation: u16,
+    #[cfg_attr(any(feature = "serde-serialize", target_family = "wasm"), serde(skip))]
+    pub(crate) store: StoreTag,
+}
+
+/// Identifies the store which created a [SourceId]. Zero sized without the `source-id-store-tags`
+/// feature, so always equal
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub(crate) struct StoreTag(#[cfg(feature = "source-id-store-tags")] u16);
+
+impl StoreTag {
+    /// For [SourceId::NULL](crate::Nullable::NULL) and the process-wide global store
+    pub(crate) const UNTAGGED: Self = Self::from_u16(0);
+}
+
+#[cfg(feature = "source-id-store-tags")]
+impl StoreTag {
+    /// A tag different to other stores in this process (until 65,535 stores have been created).
+    /// Tags start from a random value in each process, so stores restored from snapshots (which
+    /// keep the tag of the store they were taken from) are unlikely to clash with new stores
+    pub(crate) fn new() -> Self {
+        use std::{
+            collections::hash_map::RandomState,
+            hash::{BuildHasher, Hasher},
+            sync::{
+                atomic::{AtomicU16, Ordering},
+                OnceLock,
+            },
+        };
+
+        static BASE: OnceLock<u16> = OnceLock::new();
+        static COUNT: AtomicU16 = AtomicU16::new(0);
+        let base = *BASE.get_or_init(|| RandomState::new().build_hasher().finish() as u16);
+        loop {
+            let tag = base.wrapping_add(COUNT.fetch_add(1, Ordering::Relaxed));
+            if tag != 0 {
+                return Self(tag);
+            }
+        }
+    }
+
+    /// Tags are persisted in snapshots, so that restored stores accept existing [SourceId]s
+    pub(crate) fn to_u16(self) -> u16 {
+        self.0
+    }
+
+    pub(crate) const fn from_u16(tag: u16) -> Self {
+        Self(tag)
+    }
+}
+
+#[cfg(not(feature = "source-id-store-tags"))]
+impl StoreTag {
+    pub(crate) fn new() -> Self {
+        Self()
+    }
+
+    pub(crate) fn to_u16(self) -> u16 {
+        0
+    }
+
+    pub(crate) const fn from_u16(_tag: u16) -> Self {
+        Self()
+    }
+}
+
+impl Default for StoreTag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl fmt::Debug for SourceId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("SourceId({})", self.0))
+        if self.generation == 0 {
+            f.write_fmt(format_args!("SourceId({})", self.index))
+        } else {
+            f.write_fmt(format_args!(
+                "SourceId({}, generation {})",
+                self.index, self.generation
+            ))
+        }
     }
 }
 
 impl SourceId {
+    /// The maximum number of sources in a store
+    #[cfg(not(feature = "u32-source-ids"))]
+    pub const MAX_SOURCES: usize = u16::MAX as usize;
+    #[cfg(feature = "u32-source-ids")]
+    pub const MAX_SOURCES: usize = u32::MAX as usize;
+
     /// Returns a [SourceId] handle that references a file and its content
     pub fn new(filesystem: &mut impl FileSystem, path: PathBuf, content: String) -> Self {
         filesystem.new_source_id(path, content)
     }
+
+    /// For stores which index sources in a [Vec]. `0` is [SourceId::NULL](crate::Nullable::NULL)
+    /// so this is one more than the position
+    pub(crate) fn from_index(index: usize, generation: u16, store: StoreTag) -> Self {
+        Self {
+            index: index.try_into().unwrap_or_else(|_| {
+                panic!(
+                    "too many sources for SourceId (maximum {}). The `u32-source-ids` feature allows more",
+                    SourceId::MAX_SOURCES
+                )
+            }),
+            generation,
+            store,
+        }
+    }
+
+    /// For [Debug] of spans and positions. `index` or `index.generation` if the generation is
+    /// not zero
+    pub(crate) fn short_debug(self) -> String {
+        if self.generation == 0 {
+            self.index.to_string()
+        } else {
+            format!("{}.{}", self.index, self.generation)
+        }
+    }
+
+    /// Position of the source in a [Vec]. Not valid for [SourceId::NULL](crate::Nullable::NULL)
+    pub(crate) fn position(self) -> usize {
+        self.index as usize - 1
+    }
 }
 
 #[cfg(feature = "self-rust-tokenize")]
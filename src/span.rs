@@ -1,5 +1,5 @@
 use super::SourceId;
-use crate::{encodings::*, FileSystem};
+use crate::{encodings::*, Error, FileSystem};
 use std::{any::TypeId, convert::TryInto, fmt, ops::Range};
 
 /// For serialization checking
@@ -41,7 +41,11 @@ impl Nullable for () {
 }
 
 impl Nullable for SourceId {
-    const NULL: Self = SourceId(0);
+    const NULL: Self = SourceId {
+        index: 0,
+        generation: 0,
+        store: crate::source_id::StoreTag::UNTAGGED,
+    };
 }
 
 impl<T: Nullable> Nullable for BaseSpan<T> {
@@ -56,7 +60,9 @@ impl fmt::Debug for SpanWithSource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!(
             "{}..{}#{}",
-            self.start, self.end, self.source.0
+            self.start,
+            self.end,
+            self.source.short_debug()
         ))
     }
 }
@@ -112,26 +118,34 @@ impl SpanWithSource {
         self,
         fs: &impl FileSystem,
     ) -> LineColumnSpan<T> {
-        fs.get_source_by_id(self.source, |source| {
-            let line_start = source.line_starts.get_line_pos_is_on(self.start as usize);
-            let line_start_byte = source.line_starts.0[line_start];
-            let column_start =
-                T::get_encoded_length(&source.content[line_start_byte..(self.start as usize)]);
-
-            let line_end = source.line_starts.get_line_pos_is_on(self.end as usize);
-            let line_end_byte = source.line_starts.0[line_end];
-            let column_end =
-                T::get_encoded_length(&source.content[line_end_byte..(self.end as usize)]);
+        self.try_into_line_column_span(fs)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
 
-            LineColumnSpan {
-                line_start: line_start as u32,
-                column_start: column_start as u32,
-                line_end: line_end as u32,
-                column_end: column_end as u32,
+    /// Same as [SpanWithSource::into_line_column_span] but returns an error rather than panicking if
+    /// the source does not exist or the span is out of its bounds.
+    ///
+    /// Spans in embedded and concatenated sources are converted to lines and columns in the source
+    /// they were copied from (see [FileSystem::resolve_original_span])
+    pub fn try_into_line_column_span<T: StringEncoding>(
+        self,
+        fs: &impl FileSystem,
+    ) -> Result<LineColumnSpan<T>, Error> {
+        let span = fs.try_resolve_original_span(self)?;
+        fs.try_get_source_by_id(span.source, |source| {
+            let (line_start, column_start) =
+                source.get_line_and_column::<T>(span.start as usize)?;
+            let (line_end, column_end) = source.get_line_and_column::<T>(span.end as usize)?;
+
+            Ok(LineColumnSpan {
+                line_start,
+                column_start,
+                line_end,
+                column_end,
                 encoding: T::new(),
-                source: self.source,
-            }
-        })
+                source: span.source,
+            })
+        })?
     }
 
     pub fn without_source(self) -> Span {
@@ -238,7 +252,7 @@ pub struct Position(pub u32, pub SourceId);
 
 impl fmt::Debug for Position {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("{}#{}", self.0, self.1 .0))
+        f.write_fmt(format_args!("{}#{}", self.0, self.1.short_debug()))
     }
 }
 
@@ -247,18 +261,31 @@ impl Position {
         self,
         fs: &impl FileSystem,
     ) -> LineColumnPosition<T> {
-        fs.get_source_by_id(self.1, |source| {
-            let line = source.line_starts.get_line_pos_is_on(self.0 as usize);
-            let line_byte = source.line_starts.0[line];
-            let column =
-                T::get_encoded_length(&source.content[line_byte..(self.0 as usize)]) as u32;
-            LineColumnPosition {
-                line: line as u32,
+        self.try_into_line_column_position(fs)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [Position::into_line_column_position] but returns an error rather than panicking if
+    /// the source does not exist or the position is out of its bounds. Positions in embedded and
+    /// concatenated sources are converted to positions in the source they were copied from
+    pub fn try_into_line_column_position<T: StringEncoding>(
+        self,
+        fs: &impl FileSystem,
+    ) -> Result<LineColumnPosition<T>, Error> {
+        let span = fs.try_resolve_original_span(SpanWithSource {
+            start: self.0,
+            end: self.0,
+            source: self.1,
+        })?;
+        fs.try_get_source_by_id(span.source, |source| {
+            let (line, column) = source.get_line_and_column::<T>(span.start as usize)?;
+            Ok(LineColumnPosition {
+                line,
                 column,
                 encoding: T::new(),
-                source: self.1,
-            }
-        })
+                source: span.source,
+            })
+        })?
     }
 }
 
@@ -273,12 +300,17 @@ pub struct LineColumnPosition<T: StringEncoding> {
 
 impl<T: StringEncoding> LineColumnPosition<T> {
     pub fn into_scalar_position(self, fs: &impl FileSystem) -> Position {
-        fs.get_source_by_id(self.source, |source| {
-            let line_byte = source.line_starts.0[self.line as usize];
-            let column_length =
-                T::encoded_length_to_byte_count(&source.content[line_byte..], self.column as usize);
-            Position((line_byte + column_length).try_into().unwrap(), self.source)
-        })
+        self.try_into_scalar_position(fs)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [LineColumnPosition::into_scalar_position] but returns an error rather than
+    /// panicking if the source does not exist or the line is out of its bounds
+    pub fn try_into_scalar_position(self, fs: &impl FileSystem) -> Result<Position, Error> {
+        fs.try_get_source_by_id(self.source, |source| {
+            let position = source.get_byte_position::<T>(self.line, self.column)?;
+            Ok(Position(position, self.source))
+        })?
     }
 }
 
@@ -295,49 +327,44 @@ pub struct LineColumnSpan<T: StringEncoding> {
 
 impl<T: StringEncoding> LineColumnSpan<T> {
     pub fn into_scalar_span(self, fs: &impl FileSystem) -> SpanWithSource {
-        fs.get_source_by_id(self.source, |source| {
-            let line_start_byte = source.line_starts.0[self.line_start as usize];
-            let column_start_length = T::encoded_length_to_byte_count(
-                &source.content[line_start_byte..],
-                self.column_start as usize,
-            );
-
-            let line_end_byte = source.line_starts.0[self.line_end as usize];
-            let column_end_length = T::encoded_length_to_byte_count(
-                &source.content[line_end_byte..],
-                self.column_start as usize,
-            );
-
-            SpanWithSource {
-                start: (line_start_byte + column_start_length).try_into().unwrap(),
-                end: (line_end_byte + column_end_length).try_into().unwrap(),
+        self.try_into_scalar_span(fs)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [LineColumnSpan::into_scalar_span] but returns an error rather than panicking if
+    /// the source does not exist or the lines are out of its bounds
+    pub fn try_into_scalar_span(self, fs: &impl FileSystem) -> Result<SpanWithSource, Error> {
+        fs.try_get_source_by_id(self.source, |source| {
+            Ok(SpanWithSource {
+                start: source.get_byte_position::<T>(self.line_start, self.column_start)?,
+                end: source.get_byte_position::<T>(self.line_end, self.column_end)?,
                 source: self.source,
-            }
-        })
+            })
+        })?
     }
 }
 
 #[cfg(feature = "lsp-types-morphisms")]
-impl Into<lsp_types::Position> for LineColumnPosition<Utf8> {
-    fn into(self) -> lsp_types::Position {
+impl From<LineColumnPosition<Utf8>> for lsp_types::Position {
+    fn from(position: LineColumnPosition<Utf8>) -> Self {
         lsp_types::Position {
-            line: self.line,
-            character: self.column,
+            line: position.line,
+            character: position.column,
         }
     }
 }
 
 #[cfg(feature = "lsp-types-morphisms")]
-impl Into<lsp_types::Range> for LineColumnSpan<Utf8> {
-    fn into(self) -> lsp_types::Range {
+impl From<LineColumnSpan<Utf8>> for lsp_types::Range {
+    fn from(span: LineColumnSpan<Utf8>) -> Self {
         lsp_types::Range {
             start: lsp_types::Position {
-                line: self.line_start,
-                character: self.column_start,
+                line: span.line_start,
+                character: span.column_start,
             },
             end: lsp_types::Position {
-                line: self.line_end,
-                character: self.column_end,
+                line: span.line_end,
+                character: span.column_end,
             },
         }
     }
@@ -395,7 +422,7 @@ Another line";
             source,
         };
 
-        assert_eq!(&SOURCE[Range::from(paragraph_span.clone())], "paragraph");
+        assert_eq!(&SOURCE[Range::from(paragraph_span)], "paragraph");
         assert_eq!(
             paragraph_span.into_line_column_span(&fs),
             LineColumnSpan {
@@ -460,4 +487,48 @@ Another line";
             "lines\nAnother line"
         );
     }
+
+    #[test]
+    fn fallible_conversions() {
+        let (fs, source) = get_file_system_and_source();
+
+        let out_of_bounds = SpanWithSource {
+            start: 10,
+            end: 1000,
+            source,
+        };
+        assert_eq!(
+            out_of_bounds.try_into_line_column_span::<Utf8>(&fs),
+            Err(Error::PositionOutOfBounds {
+                position: 1000,
+                length: SOURCE.len()
+            })
+        );
+
+        let null_source = SpanWithSource::NULL;
+        assert_eq!(
+            null_source.try_into_line_column_span::<Utf8>(&fs),
+            Err(Error::NullSourceId)
+        );
+
+        let unknown_source = SourceId { index: 2, ..source };
+        assert_eq!(
+            Position(0, unknown_source).try_into_line_column_position::<Utf8>(&fs),
+            Err(Error::UnknownSourceId(unknown_source))
+        );
+
+        let past_last_line = LineColumnPosition {
+            line: 3,
+            column: 0,
+            source,
+            encoding: Utf8,
+        };
+        assert_eq!(
+            past_last_line.try_into_scalar_position(&fs),
+            Err(Error::LineOutOfBounds {
+                line: 3,
+                line_count: 3
+            })
+        );
+    }
 }
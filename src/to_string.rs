@@ -1,5 +1,6 @@
 use crate::{
-    count_characters_on_last_line, FileSystem, SourceMap, SourceMapBuilder, SpanWithSource,
+    count_characters_on_last_line, FileSystem, LineTerminators, SourceMap, SourceMapBuilder,
+    SpanWithSource,
 };
 
 /// A trait for defining behavior of adding content to a buffer. As well as register markers for source maps
@@ -51,7 +52,7 @@ impl ToString for String {
     fn add_mapping(&mut self, _source_span: &SpanWithSource) {}
 
     fn characters_on_current_line(&self) -> u32 {
-        count_characters_on_last_line(self)
+        count_characters_on_last_line(self, LineTerminators::LineFeed)
     }
 }
 
@@ -70,29 +71,35 @@ impl<T: std::io::Write> ToString for Writable<T> {
         self.length += char_size as u32;
         self.since_new_line += char_size as u32;
         self.writable.write_all(buf).unwrap();
+        if let Some(ref mut sm) = self.source_map {
+            sm.add_to_column(chr.len_utf16());
+        }
     }
 
     fn push_new_line(&mut self) {
         self.length += 1;
-        self.writable.write_all(&[b'\n']).unwrap();
+        self.writable.write_all(b"\n").unwrap();
     }
 
     fn push_str(&mut self, string: &str) {
         self.length += string.len() as u32;
         self.since_new_line += string.len() as u32;
         self.writable.write_all(string.as_bytes()).unwrap();
+        if let Some(ref mut sm) = self.source_map {
+            sm.add_to_column(string.chars().count());
+        }
     }
 
     fn push_str_contains_new_line(&mut self, slice: &str) {
         self.length += slice.len() as u32;
         self.writable.write_all(slice.as_bytes()).unwrap();
-        if let Some(ref mut sm) = self.source_map {
-            slice
-                .chars()
-                .filter(|chr| *chr == '\n')
-                .for_each(|_| sm.add_new_line());
-        }
-        self.since_new_line = count_characters_on_last_line(slice);
+        let line_terminators = if let Some(ref mut sm) = self.source_map {
+            sm.add_new_lines_in(slice);
+            sm.line_terminators()
+        } else {
+            LineTerminators::LineFeed
+        };
+        self.since_new_line = count_characters_on_last_line(slice, line_terminators);
     }
 
     fn add_mapping(&mut self, source_span: &SpanWithSource) {
@@ -180,13 +187,13 @@ impl ToString for StringWithOptionalSourceMap {
 
     fn push_str_contains_new_line(&mut self, slice: &str) {
         self.source.push_str(slice);
-        if let Some(ref mut sm) = self.source_map {
-            slice
-                .chars()
-                .filter(|chr| *chr == '\n')
-                .for_each(|_| sm.add_new_line());
-        }
-        self.since_new_line = count_characters_on_last_line(slice);
+        let line_terminators = if let Some(ref mut sm) = self.source_map {
+            sm.add_new_lines_in(slice);
+            sm.line_terminators()
+        } else {
+            LineTerminators::LineFeed
+        };
+        self.since_new_line = count_characters_on_last_line(slice, line_terminators);
     }
 
     fn add_mapping(&mut self, source_span: &SpanWithSource) {
@@ -197,7 +204,7 @@ impl ToString for StringWithOptionalSourceMap {
 
     fn should_halt(&self) -> bool {
         self.quit_after
-            .map_or(false, |quit_after| self.source.len() > quit_after)
+            .is_some_and(|quit_after| self.source.len() > quit_after)
     }
 
     fn characters_on_current_line(&self) -> u32 {